@@ -0,0 +1,92 @@
+use std::collections::BTreeSet;
+use std::fs::read_dir;
+
+use crate::config::Config;
+use crate::crateversion::CrateVersion;
+use crate::util::read_installed_packages;
+use crate::EXIT_FAILED;
+
+/// Installed by rustup into the same directory, not tracked by cargo
+const RUSTUP_PROXIES: [&str; 14] = [
+    "cargo", "cargo-clippy", "cargo-fmt", "cargo-miri", "clippy-driver", "rls", "rust-analyzer",
+    "rust-gdb", "rust-gdbgui", "rust-lldb", "rustc", "rustdoc", "rustfmt", "rustup",
+];
+
+/// Compares the binaries recorded by cargo with the files in `bin`.
+/// With `repair` crates with missing binaries are installed again.
+pub fn run(cfg: &Config, repair: bool) -> i32 {
+    let mut installed = match read_installed_packages(cfg) {
+        Ok(installed) => installed,
+        Err(e) => {
+            eprintln!("Could not read installed packages: {}", e);
+            return EXIT_FAILED;
+        }
+    };
+    let untracked = untracked(cfg, &installed);
+    for msg in cfg.select(&mut installed) {
+        eprintln!("{}", msg);
+    }
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let broken: Vec<&CrateVersion> = installed.iter()
+        .filter(|cv| cv.binaries.iter().any(|b| !b.exists()))
+        .collect();
+    for cv in &broken {
+        let missing: Vec<String> = cv.binaries.iter()
+            .filter(|b| !b.exists())
+            .filter_map(|b| b.file_name())
+            .map(|b| b.to_string_lossy().into_owned())
+            .collect();
+        println!("{} {} is missing {}", cv.name, cv.version, missing.join(", "));
+    }
+    if !untracked.is_empty() {
+        println!("Files in {} that no crate installed:", cfg.cpath.join("bin").display());
+        for file in &untracked {
+            println!("    {}", file);
+        }
+    }
+    if broken.is_empty() && untracked.is_empty() {
+        println!("All recorded binaries are installed.");
+    }
+
+    if !repair || broken.is_empty() {
+        return if broken.is_empty() { 0 } else { EXIT_FAILED };
+    }
+    let mut failed = Vec::new();
+    for cv in broken {
+        if !cfg.upgrade {
+            println!("Reinstall {} {}", cv.name, cv.version);
+        } else {
+            println!("Reinstalling {} {}", cv.name, cv.version);
+            if !cv.reinstall(cfg) {
+                failed.push(cv.name.as_str());
+            }
+        }
+    }
+    if failed.is_empty() {
+        0
+    } else {
+        println!("Reinstallation failed for {} crate(s): {}", failed.len(), failed.join(", "));
+        EXIT_FAILED
+    }
+}
+
+/// Files in `bin` that are neither recorded by cargo nor rustup proxies
+fn untracked(cfg: &Config, installed: &[CrateVersion]) -> Vec<String> {
+    let tracked: BTreeSet<String> = installed.iter()
+        .flat_map(|cv| &cv.binaries)
+        .filter_map(|b| b.file_name())
+        .map(|b| b.to_string_lossy().into_owned())
+        .collect();
+    let Ok(entries) = read_dir(cfg.cpath.join("bin")) else { return Vec::new() };
+    let mut files: Vec<String> = entries.flatten()
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|f| !f.starts_with('.') && !tracked.contains(f))
+        .filter(|f| {
+            let stem = f.strip_suffix(std::env::consts::EXE_SUFFIX).unwrap_or(f);
+            !RUSTUP_PROXIES.contains(&stem)
+        })
+        .collect();
+    files.sort();
+    files
+}
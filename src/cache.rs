@@ -0,0 +1,254 @@
+use semver::Version;
+use serde_json::{json, Map, Value};
+
+use std::fs::{metadata, read_to_string, remove_file, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::crateversion::{PackageSource, Result};
+use crate::error::UpgradeError;
+use crate::util::write_atomic;
+
+const CACHE_FILE: &str = ".install-upgrade-cache.json";
+const LOCK_FILE: &str = ".install-upgrade-cache.lock";
+/// Locks older than this are considered left over from a killed run
+const STALE_LOCK: Duration = Duration::from_secs(30);
+
+/// A cached remote lookup
+#[derive(Debug)]
+pub struct Entry {
+    pub version: Version,
+    pub commit: Option<String>,
+    pub bins: Option<Vec<String>>,
+    pub features: Option<Vec<String>>,
+    pub age: Duration,
+}
+
+/// Remote versions of previous runs, stored in the cargo home
+#[derive(Debug)]
+pub struct Cache {
+    path: PathBuf,
+    lock: PathBuf,
+    ttl: Duration,
+    entries: Map<String, Value>,
+    changed: Map<String, Value>,
+}
+
+impl Cache {
+    /// Loads the cache of the cargo home. A missing or damaged file yields an empty cache.
+    pub fn load(cfg: &Config) -> Cache {
+        let path = cfg.cpath.join(CACHE_FILE);
+        Cache {
+            entries: read_entries(&path),
+            lock: cfg.cpath.join(LOCK_FILE),
+            path,
+            ttl: cfg.cache_ttl,
+            changed: Map::new(),
+        }
+    }
+
+    /// Cache key for the remote of a package
+    pub fn key(name: &str, source: &PackageSource) -> Option<String> {
+        match *source {
+            PackageSource::CratesIo => Some(format!("registry:{}", name)),
+            PackageSource::Git { ref url, .. } => Some(format!("git:{}#{}", url, name)),
+            PackageSource::Local { .. } => None,
+        }
+    }
+
+    /// Returns the entry for `key` if it is younger than the TTL
+    pub fn get(&self, key: &str) -> Option<Entry> {
+        let entry = self.entries.get(key)?;
+        let time = UNIX_EPOCH + Duration::from_secs(entry["time"].as_u64()?);
+        let age = SystemTime::now().duration_since(time).unwrap_or_default();
+        if age >= self.ttl {
+            return None;
+        }
+        Some(Entry {
+            version: Version::parse(entry["version"].as_str()?).ok()?,
+            commit: entry["commit"].as_str().map(String::from),
+            bins: strings(&entry["bins"]),
+            features: strings(&entry["features"]),
+            age,
+        })
+    }
+
+    /// Stores an entry, its age is ignored
+    pub fn insert(&mut self, key: String, entry: &Entry) {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let entry = json!({
+            "version": entry.version.to_string(),
+            "commit": entry.commit,
+            "bins": entry.bins,
+            "features": entry.features,
+            "time": time,
+        });
+        self.entries.insert(key.clone(), entry.clone());
+        self.changed.insert(key, entry);
+    }
+
+    /// Adds bin targets and features looked up after the version to the entry for `key`,
+    /// if it is still about `version`. The age of the entry is kept.
+    pub fn update(&mut self, key: &str, version: &Version, bins: Option<&[String]>, features: Option<&[String]>) {
+        let Some(entry) = self.entries.get_mut(key) else { return };
+        if entry["version"].as_str() != Some(&version.to_string()) {
+            return;
+        }
+        if let Some(bins) = bins {
+            entry["bins"] = json!(bins);
+        }
+        if let Some(features) = features {
+            entry["features"] = json!(features);
+        }
+        self.changed.insert(key.to_owned(), entry.clone());
+    }
+
+    /// Writes new entries back to disk. Entries written by concurrent runs are kept.
+    pub fn store(&self) -> Result<()> {
+        if self.changed.is_empty() || self.ttl.is_zero() {
+            return Ok(());
+        }
+        self.acquire_lock()?;
+        let mut entries = read_entries(&self.path);
+        entries.extend(self.changed.clone());
+        let res = serde_json::to_string_pretty(&Value::Object(entries))
+            .map_err(UpgradeError::from)
+            .and_then(|s| write_atomic(&self.path, &s));
+        let _ = remove_file(&self.lock);
+        res
+    }
+
+    fn acquire_lock(&self) -> Result<()> {
+        for _ in 0..50 {
+            match OpenOptions::new().write(true).create_new(true).open(&self.lock) {
+                Ok(_) => return Ok(()),
+                Err(_) => {
+                    let stale = metadata(&self.lock)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|m| SystemTime::now().duration_since(m).ok())
+                        .is_some_and(|age| age > STALE_LOCK);
+                    if stale {
+                        let _ = remove_file(&self.lock);
+                    } else {
+                        sleep(Duration::from_millis(100));
+                    }
+                }
+            }
+        }
+        Err(UpgradeError::Gen(format!("cache is locked by {}", self.lock.display())))
+    }
+}
+
+fn strings(value: &Value) -> Option<Vec<String>> {
+    value.as_array().map(|a| a.iter().filter_map(Value::as_str).map(String::from).collect())
+}
+
+fn read_entries(path: &Path) -> Map<String, Value> {
+    read_to_string(path).ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .and_then(|v| match v {
+            Value::Object(m) => Some(m),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(ttl: u64, entries: Value) -> Cache {
+        let Value::Object(entries) = entries else { panic!("entries are no object") };
+        Cache {
+            path: PathBuf::new(),
+            lock: PathBuf::new(),
+            ttl: Duration::from_secs(ttl),
+            entries,
+            changed: Map::new(),
+        }
+    }
+
+    /// Seconds since the epoch `age` seconds ago
+    fn ago(age: u64) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - age
+    }
+
+    #[test]
+    fn ttl_boundary() {
+        let cache = cache(60, json!({
+            "registry:fresh": { "version": "1.0.0", "time": ago(0) },
+            "registry:young": { "version": "1.0.0", "time": ago(58) },
+            "registry:expired": { "version": "1.0.0", "time": ago(60) },
+            "registry:future": { "version": "1.0.0", "time": ago(0) + 3600 },
+        }));
+        assert!(cache.get("registry:fresh").is_some());
+        assert!(cache.get("registry:young").unwrap().age >= Duration::from_secs(58));
+        assert!(cache.get("registry:expired").is_none());
+        // clocks that went backwards count as a fresh entry
+        assert_eq!(cache.get("registry:future").unwrap().age, Duration::ZERO);
+        assert!(cache.get("registry:missing").is_none());
+    }
+
+    #[test]
+    fn zero_ttl_disables_the_cache() {
+        let cache = cache(0, json!({ "registry:foo": { "version": "1.0.0", "time": ago(0) } }));
+        assert!(cache.get("registry:foo").is_none());
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let cache = cache(60, json!({
+            "registry:no-time": { "version": "1.0.0" },
+            "registry:no-version": { "time": ago(0) },
+            "registry:bad-version": { "version": "1.x", "time": ago(0) },
+            "registry:string": "1.0.0",
+            "registry:bins": { "version": "1.0.0", "time": ago(0), "bins": ["rg", 1], "features": "all" },
+        }));
+        assert!(cache.get("registry:no-time").is_none());
+        assert!(cache.get("registry:no-version").is_none());
+        assert!(cache.get("registry:bad-version").is_none());
+        assert!(cache.get("registry:string").is_none());
+        let entry = cache.get("registry:bins").unwrap();
+        assert_eq!(entry.bins.unwrap(), ["rg"]);
+        assert!(entry.features.is_none());
+        assert!(entry.commit.is_none());
+    }
+
+    #[test]
+    fn insert_and_update() {
+        let mut cache = cache(60, json!({}));
+        let version = Version::new(1, 2, 0);
+        cache.insert(String::from("git:https://example.com/foo#foo"), &Entry {
+            version: version.clone(),
+            commit: Some(String::from("0123abc")),
+            bins: None,
+            features: None,
+            age: Duration::from_secs(3600),
+        });
+        let bins = [String::from("foo")];
+        cache.update("git:https://example.com/foo#foo", &Version::new(1, 1, 0), Some(&bins), None);
+        assert!(cache.get("git:https://example.com/foo#foo").unwrap().bins.is_none());
+        cache.update("git:https://example.com/foo#foo", &version, Some(&bins), Some(&[]));
+        let entry = cache.get("git:https://example.com/foo#foo").unwrap();
+        assert!(entry.age < Duration::from_secs(60));
+        assert_eq!(entry.commit.as_deref(), Some("0123abc"));
+        assert_eq!(entry.bins.unwrap(), ["foo"]);
+        assert_eq!(entry.features.unwrap(), Vec::<String>::new());
+        assert_eq!(cache.changed.len(), 1);
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(Cache::key("foo", &PackageSource::CratesIo).as_deref(), Some("registry:foo"));
+        let git = PackageSource::Git {
+            url: String::from("https://example.com/foo"),
+            commit: String::from("0123abc"),
+            remote_commit: String::new(),
+        };
+        assert_eq!(Cache::key("foo", &git).as_deref(), Some("git:https://example.com/foo#foo"));
+        assert!(Cache::key("foo", &PackageSource::Local { path: PathBuf::from("/foo") }).is_none());
+    }
+}
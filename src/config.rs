@@ -1,19 +1,420 @@
-use std::path::PathBuf;
+use toml::{Table, Value};
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::crateversion::{CrateVersion, Result, SourceKind};
+use crate::error::UpgradeError;
+use crate::util::wildcard_match;
+
+/// Name of the configuration file in the cargo home
+pub const CONFIG_FILE: &str = "install-upgrade.toml";
 
 /// Holds the settings of the current run
 #[derive(Debug)]
 pub struct Config {
     pub upgrade: bool,
+    pub check: bool,
     pub force: bool,
     pub verbose: bool,
+    pub offline: bool,
+    pub refresh: bool,
+    pub pull: bool,
+    pub recover: Option<Recovery>,
+    pub verify: bool,
+    pub allow_conflicts: bool,
+    pub cache_ttl: Duration,
+    /// Lines of the build log shown when an upgrade fails
+    pub log_lines: usize,
+    /// Retries of steps that failed with a transient error
+    pub retries: u32,
+    /// Limits for commands that look up versions and for builds
+    pub lookup_timeout: Option<Duration>,
+    pub build_timeout: Option<Duration>,
+    /// Limit for hooks and test commands
+    pub hook_timeout: Option<Duration>,
     pub mode: PackageMode,
+    /// Kinds of sources to select, all if empty
+    pub sources: Vec<SourceKind>,
     pub cpath: PathBuf,
+    pub settings: Settings,
+}
+
+impl Config {
+    /// The check mode only prints if verbose output is requested
+    pub fn quiet(&self) -> bool {
+        self.check && !self.verbose
+    }
+
+    /// Keeps the crates selected by the package and source filters.
+    /// A binary name of several crates selects none of them, but excludes all of them.
+    /// Returns messages about patterns that select nothing or are ambiguous.
+    pub fn select(&self, installed: &mut Vec<CrateVersion>) -> Vec<String> {
+        let mut messages = Vec::new();
+        let (patterns, include) = match self.mode {
+            PackageMode::All => (&[][..], false),
+            PackageMode::Include(ref p) => (&p[..], true),
+            PackageMode::Exclude(ref p) => (&p[..], false),
+        };
+        let mut matched = HashSet::new();
+        for pattern in patterns {
+            let found = pattern.select(installed);
+            match found.len() {
+                0 if !include => {},
+                0 if pattern.is_name() => messages.push(format!("{} is not installed.", pattern)),
+                0 => messages.push(format!("No installed crate matches {}.", pattern)),
+                2.. if pattern.is_name() && include => {
+                    let names: Vec<&str> = found.iter().map(|cv| cv.name.as_str()).collect();
+                    messages.push(format!("{} is ambiguous, it is a binary of {}. Use the crate name.",
+                                          pattern, names.join(", ")));
+                }
+                _ => matched.extend(found.into_iter().map(|cv| cv.name.clone())),
+            }
+        }
+        installed.retain(|cv| match self.mode {
+            PackageMode::All => true,
+            _ => matched.contains(&cv.name) == include,
+        });
+        installed.retain(|cv| self.sources.is_empty() || self.sources.contains(&cv.source.kind()));
+        messages
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// Settings of a dry run on the cargo home `cpath`, for unit tests
+    pub fn for_test(cpath: &Path) -> Config {
+        Config {
+            upgrade: false,
+            check: false,
+            force: false,
+            verbose: false,
+            offline: true,
+            refresh: false,
+            pull: false,
+            recover: None,
+            verify: false,
+            allow_conflicts: false,
+            cache_ttl: Duration::ZERO,
+            log_lines: 20,
+            retries: 0,
+            lookup_timeout: None,
+            build_timeout: None,
+            hook_timeout: None,
+            mode: PackageMode::All,
+            sources: Vec::new(),
+            cpath: cpath.to_path_buf(),
+            settings: Settings::default(),
+        }
+    }
+}
+
+/// How to deal with upgrades interrupted by a crash
+#[derive(Debug, Clone, Copy)]
+pub enum Recovery {
+    Restore,
+    Finish,
+    Discard,
+}
+
+/// Output format of the listing subcommands
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+/// When to color the output
+#[derive(Debug, Clone, Copy)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
 }
 
 /// Settings for the current run
 #[derive(Debug)]
 pub enum PackageMode {
     All,
-    Include(Vec<String>),
-    Exclude(Vec<String>),
+    Include(Vec<Pattern>),
+    Exclude(Vec<Pattern>),
+}
+
+/// Crate names are compared like on crates.io, ignoring case and `-` vs `_`
+fn normalize(name: &str) -> String {
+    name.to_lowercase().replace('-', "_")
+}
+
+/// Selects crates by name or binary
+#[derive(Debug)]
+pub enum Pattern {
+    /// A name with the wildcards `*` and `?`
+    Glob(String),
+    /// The expression as given and compiled
+    #[cfg(feature = "regex")]
+    Regex(String, regex::Regex),
+}
+
+impl Pattern {
+    /// A regular expression that has to match the whole name
+    #[cfg(feature = "regex")]
+    pub fn regex(re: &str) -> Result<Pattern> {
+        regex::Regex::new(&format!("^(?:{})$", re))
+            .map(|compiled| Pattern::Regex(re.to_owned(), compiled))
+            .map_err(|e| UpgradeError::Parse(format!("regex {}: {}", re, e)))
+    }
+
+    /// Matches a crate name
+    pub fn matches(&self, name: &str) -> bool {
+        match *self {
+            Pattern::Glob(ref glob) => wildcard_match(&normalize(glob), &normalize(name)),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(_, ref re) => re.is_match(name),
+        }
+    }
+
+    /// Matches a binary name. Binaries are files, their names are compared exactly.
+    pub fn matches_bin(&self, bin: &str) -> bool {
+        match *self {
+            Pattern::Glob(ref glob) => wildcard_match(glob, bin),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(_, ref re) => re.is_match(bin),
+        }
+    }
+
+    /// True for a plain crate name
+    pub fn is_name(&self) -> bool {
+        matches!(*self, Pattern::Glob(ref glob) if !glob.contains(['*', '?']))
+    }
+
+    /// Crates matching by name or binary. A plain name selects the crate of that name,
+    /// or else the crates with a binary of that name.
+    fn select<'a>(&self, installed: &'a [CrateVersion]) -> Vec<&'a CrateVersion> {
+        let by_name: Vec<&CrateVersion> = installed.iter().filter(|cv| self.matches(&cv.name)).collect();
+        if self.is_name() && !by_name.is_empty() {
+            return by_name;
+        }
+        installed.iter()
+            .filter(|cv| self.matches(&cv.name) || cv.bin_names().iter().any(|b| self.matches_bin(b)))
+            .collect()
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Pattern::Glob(ref text) => f.write_str(text),
+            #[cfg(feature = "regex")]
+            Pattern::Regex(ref text, _) => f.write_str(text),
+        }
+    }
+}
+
+/// Commands run before and after an upgrade
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pub pre_upgrade: Option<String>,
+    pub post_upgrade: Option<String>,
+}
+
+/// Settings of a single crate
+#[derive(Debug, Default)]
+pub struct CrateSettings {
+    pub hooks: Hooks,
+    /// Command verifying the installed binaries, replaces the `--version` check
+    pub test: Option<String>,
+}
+
+/// Contents of the configuration file
+///
+/// ```toml
+/// pre_upgrade = "echo global"
+/// verify = true
+///
+/// [crates.ripgrep]
+/// post_upgrade = "rg --generate complete-bash > ~/.local/share/bash-completion/completions/rg"
+/// test = "rg --version"
+/// ```
+#[derive(Debug, Default)]
+pub struct Settings {
+    pub hooks: Hooks,
+    /// Verify all upgrades
+    pub verify: bool,
+    pub crates: HashMap<String, CrateSettings>,
+}
+
+impl Settings {
+    /// Reads the configuration file. A missing file yields the defaults.
+    pub fn load(path: &Path) -> Result<Settings> {
+        let content = match read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let table: Table = content.parse()?;
+        let mut settings = Settings {
+            hooks: Hooks::from_table(&table)?,
+            verify: match table.get("verify") {
+                None => false,
+                Some(Value::Boolean(b)) => *b,
+                Some(_) => return Err(invalid("verify")),
+            },
+            crates: HashMap::new(),
+        };
+        if let Some(crates) = table.get("crates") {
+            let crates = crates.as_table().ok_or_else(|| invalid("crates"))?;
+            for (name, value) in crates {
+                let value = value.as_table().ok_or_else(|| invalid(name))?;
+                settings.crates.insert(name.clone(), CrateSettings {
+                    hooks: Hooks::from_table(value)?,
+                    test: get_string(value, "test")?,
+                });
+            }
+        }
+        Ok(settings)
+    }
+
+    pub fn for_crate(&self, name: &str) -> Option<&CrateSettings> {
+        self.crates.get(name)
+    }
+}
+
+impl Hooks {
+    fn from_table(table: &Table) -> Result<Hooks> {
+        Ok(Hooks {
+            pre_upgrade: get_string(table, "pre_upgrade")?,
+            post_upgrade: get_string(table, "post_upgrade")?,
+        })
+    }
+}
+
+fn get_string(table: &Table, key: &str) -> Result<Option<String>> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(invalid(key)),
+    }
+}
+
+fn invalid(key: &str) -> UpgradeError {
+    UpgradeError::Parse(format!("{}: invalid value for {}", CONFIG_FILE, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semver::Version;
+
+    fn config(mode: PackageMode, sources: Vec<SourceKind>) -> Config {
+        Config { mode, sources, ..Config::for_test(Path::new("")) }
+    }
+
+    fn installed() -> Vec<CrateVersion> {
+        let mut crates = Vec::new();
+        for (name, bins) in [("ripgrep", &["rg"][..]), ("cargo-edit", &["cargo-add", "cargo-rm"]),
+                             ("fd-find", &["fd"]), ("fd", &["fdx"]), ("semver_tool", &["semv"]), ("semv-alt", &["semv"])] {
+            let mut cv = CrateVersion::new(name, Version::new(1, 0, 0));
+            cv.set_binaries(&bins.iter().map(PathBuf::from).collect::<Vec<_>>());
+            crates.push(cv);
+        }
+        crates[1].set_repo("https://github.com/killercup/cargo-edit", "0123abc");
+        crates[2].set_path("/home/u/fd");
+        crates
+    }
+
+    fn glob(patterns: &[&str]) -> Vec<Pattern> {
+        patterns.iter().map(|p| Pattern::Glob(p.to_string())).collect()
+    }
+
+    fn select(mode: PackageMode, sources: Vec<SourceKind>) -> (Vec<String>, Vec<String>) {
+        let mut crates = installed();
+        let messages = config(mode, sources).select(&mut crates);
+        (crates.into_iter().map(|cv| cv.name).collect(), messages)
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let pattern = Pattern::Glob("cargo-*".to_owned());
+        assert!(pattern.matches("cargo-edit"));
+        assert!(!pattern.matches("ripgrep"));
+        assert!(Pattern::Glob(String::new()).matches(""));
+        assert!(Pattern::Glob("ripgrep".to_owned()).is_name());
+        assert!(!Pattern::Glob("r?".to_owned()).is_name());
+        assert!(!Pattern::Glob("*".to_owned()).is_name());
+    }
+
+    #[test]
+    fn select_all() {
+        let (crates, messages) = select(PackageMode::All, Vec::new());
+        assert_eq!(crates.len(), installed().len());
+        assert!(messages.is_empty());
+        let (crates, _) = select(PackageMode::All, vec![SourceKind::Git, SourceKind::Local]);
+        assert_eq!(crates, ["cargo-edit", "fd-find"]);
+    }
+
+    #[test]
+    fn select_globs() {
+        let (crates, messages) = select(PackageMode::Include(glob(&["ripgrep", "cargo-*"])), Vec::new());
+        assert_eq!(crates, ["ripgrep", "cargo-edit"]);
+        assert!(messages.is_empty());
+        let (crates, _) = select(PackageMode::Include(glob(&["cargo-*"])), vec![SourceKind::Registry]);
+        assert!(crates.is_empty());
+        let (crates, messages) = select(PackageMode::Include(glob(&[])), Vec::new());
+        assert!(crates.is_empty() && messages.is_empty());
+    }
+
+    #[test]
+    fn select_reports_unknown_patterns() {
+        let (crates, messages) = select(PackageMode::Include(glob(&["nothing", "no*"])), Vec::new());
+        assert!(crates.is_empty());
+        assert_eq!(messages, ["nothing is not installed.", "No installed crate matches no*."]);
+        // excluding something that is not installed is fine
+        let (crates, messages) = select(PackageMode::Exclude(glob(&["nothing", "no*"])), Vec::new());
+        assert_eq!(crates.len(), installed().len());
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn select_exclude() {
+        let (crates, _) = select(PackageMode::Exclude(glob(&["ripgrep", "s*"])), vec![SourceKind::Registry]);
+        assert_eq!(crates, ["fd"]);
+        let (crates, _) = select(PackageMode::Exclude(glob(&[])), Vec::new());
+        assert_eq!(crates.len(), installed().len());
+    }
+
+    #[test]
+    fn names_are_normalized_binaries_are_not() {
+        let pattern = Pattern::Glob("Cargo_*".to_owned());
+        assert!(pattern.matches("cargo-edit"));
+        assert!(!pattern.matches_bin("cargo-add"));
+        assert!(Pattern::Glob("cargo-*".to_owned()).matches_bin("cargo-add"));
+        assert!(!Pattern::Glob("RG".to_owned()).matches_bin("rg"));
+        let (crates, _) = select(PackageMode::Include(glob(&["Cargo_Edit", "FD_find"])), Vec::new());
+        assert_eq!(crates, ["cargo-edit", "fd-find"]);
+    }
+
+    #[test]
+    fn select_by_binary() {
+        let (crates, messages) = select(PackageMode::Include(glob(&["rg", "cargo-rm"])), Vec::new());
+        assert_eq!(crates, ["ripgrep", "cargo-edit"]);
+        assert!(messages.is_empty());
+        // the crate name wins over a binary of another crate
+        let (crates, _) = select(PackageMode::Include(glob(&["fd"])), Vec::new());
+        assert_eq!(crates, ["fd"]);
+        let (crates, _) = select(PackageMode::Include(glob(&["fd*"])), Vec::new());
+        assert_eq!(crates, ["fd-find", "fd"]);
+    }
+
+    #[test]
+    fn shared_binaries() {
+        let (crates, messages) = select(PackageMode::Include(glob(&["semv"])), Vec::new());
+        assert!(crates.is_empty());
+        assert_eq!(messages, ["semv is ambiguous, it is a binary of semver_tool, semv-alt. Use the crate name."]);
+        let (crates, messages) = select(PackageMode::Exclude(glob(&["semv", "rg"])), Vec::new());
+        assert_eq!(crates, ["cargo-edit", "fd-find", "fd"]);
+        assert!(messages.is_empty());
+    }
 }
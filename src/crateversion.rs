@@ -3,16 +3,27 @@ use tempdir::TempDir;
 
 use std::{fmt,result};
 use std::path::{PathBuf,Path};
-use std::fs::{rename,copy,DirBuilder};
-use std::io::{Error,ErrorKind};
+use std::fs::{rename,copy,create_dir_all,metadata,read,remove_file,DirBuilder,File};
 use std::borrow::Cow;
+use std::env::consts::EXE_SUFFIX;
+use std::time::Duration;
 
 use self::PackageSource::*;
 use crate::config::Config;
 use crate::UpgradeError;
 use crate::util::*;
+use crate::index;
+use crate::cache::{Cache, Entry};
+use crate::hooks::{run_hooks, run_shell, Stage};
+use crate::state::{fingerprint, State};
+use crate::manifest::find_package;
+use crate::journal::{Journal, BACKUP_DIR};
+use crate::progress::Progress;
 
 
+/// Build logs of the last upgrade of each crate, in the cargo home
+const LOG_DIR: &str = ".install-upgrade-logs";
+
 pub type Result<T> = result::Result<T, UpgradeError>;
 
 #[derive(PartialEq, Debug, Clone)]
@@ -28,6 +39,49 @@ pub enum PackageSource {
     },
 }
 
+/// Kind of a package source, used to filter crates
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum SourceKind {
+    Registry,
+    Git,
+    Local,
+}
+
+impl PackageSource {
+    pub fn kind(&self) -> SourceKind {
+        match *self {
+            CratesIo => SourceKind::Registry,
+            Git { .. } => SourceKind::Git,
+            Local { .. } => SourceKind::Local,
+        }
+    }
+}
+
+/// Outcome of the remote version lookup
+#[derive(PartialEq, Debug, Clone)]
+pub enum Lookup {
+    Pending,
+    Done,
+    Failed(String),
+}
+
+/// Update state of a crate
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Status {
+    UpToDate,
+    Outdated,
+    Unknown,
+}
+
+/// Result of an upgrade
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Outcome {
+    Upgraded,
+    /// Left as it is, e.g. because a pre-upgrade hook failed
+    Skipped,
+    Failed,
+}
+
 /// Version Information for Crates
 #[derive(Clone, Debug)]
 pub struct CrateVersion {
@@ -37,6 +91,25 @@ pub struct CrateVersion {
     pub source: PackageSource,
     pub binaries: Vec<PathBuf>,
     pub features: Vec<String>,
+    /// False if only `.crates.toml` recorded the crate, it lacks features and build settings
+    pub features_known: bool,
+    pub all_features: bool,
+    pub no_default_features: bool,
+    /// Build profile, target triple and rustc version recorded by cargo
+    pub profile: Option<String>,
+    pub target: Option<String>,
+    pub rustc: Option<String>,
+    pub index_age: Option<Duration>,
+    pub cache_age: Option<Duration>,
+    pub lookup: Lookup,
+    /// Current fingerprint of a local source
+    pub fingerprint: Option<String>,
+    /// Whether git/local sources changed since the installation. None if unknown.
+    pub sources_changed: Option<bool>,
+    /// Bin targets of the remote version
+    pub remote_bins: Option<Vec<String>>,
+    /// Features of the remote version
+    pub remote_features: Option<Vec<String>>,
 }
 
 impl CrateVersion {
@@ -48,13 +121,22 @@ impl CrateVersion {
             source: CratesIo,
             binaries: Vec::new(),
             features: Vec::new(),
+            features_known: true,
+            all_features: false,
+            no_default_features: false,
+            profile: None,
+            target: None,
+            rustc: None,
+            index_age: None,
+            cache_age: None,
+            lookup: Lookup::Pending,
+            fingerprint: None,
+            sources_changed: None,
+            remote_bins: None,
+            remote_features: None,
         }
     }
 
-    pub fn new_fromstr<S>(name: S, ver: S) -> CrateVersion where S: AsRef<str> {
-        CrateVersion::new(name.as_ref(), Version::parse(ver.as_ref()).unwrap())
-    }
-
     /// Sets a remote git repository as package source
     pub fn set_repo<S>(&mut self, url: S, co: S) where S: Into<String> + Clone {
         self.source = Git {
@@ -79,6 +161,25 @@ impl CrateVersion {
         self.features.extend_from_slice(feat);
     }
 
+    /// Names of the installed binaries without the executable suffix
+    pub fn bin_names(&self) -> Vec<String> {
+        self.binaries.iter()
+            .filter_map(|b| b.file_name())
+            .map(|b| {
+                let b = b.to_string_lossy();
+                b.strip_suffix(EXE_SUFFIX).unwrap_or(&b).to_owned()
+            })
+            .collect()
+    }
+
+    /// Branch, tag or revision a git package was installed from, e.g. `branch=main`
+    pub fn git_ref(&self) -> Option<&str> {
+        match self.source {
+            Git { ref url, .. } => url.split_once('?').map(|(_, query)| query),
+            _ => None,
+        }
+    }
+
     /// Returns true if the package source is Crates.io
     pub fn is_cratesio(&self) -> bool {
         matches!(self.source, CratesIo)
@@ -89,84 +190,371 @@ impl CrateVersion {
         self.version < self.remote_version
     }
 
-    /// Search the remote for new versions
-    pub fn get_remote_version(&mut self, cfg: &Config) {
+    /// States how old the remote version is if it was not looked up just now
+    pub fn age_note(&self) -> String {
+        match (self.index_age, self.cache_age) {
+            (Some(age), _) => format!(" (offline, index cache is {} old)", fmt_age(age)),
+            (_, Some(age)) => format!(" (cached {} ago)", fmt_age(age)),
+            _ => String::new(),
+        }
+    }
+
+    /// Update state based on the last remote lookup
+    pub fn status(&self) -> Status {
+        match self.lookup {
+            Lookup::Done if self.new_remote_version() => Status::Outdated,
+            Lookup::Done if self.sources_changed == Some(true) => Status::Outdated,
+            Lookup::Done => Status::UpToDate,
+            _ => Status::Unknown,
+        }
+    }
+
+    /// Search the remote for new versions and record the outcome in `lookup`
+    pub fn get_remote_version(&mut self, cfg: &Config, cache: &mut Cache) {
+        let what = format!("Lookup of {}", self.name);
+        self.lookup = match retry(cfg, &what, || self.cached_lookup(cfg, cache)) {
+            Ok(ver) => {
+                debug!("Remote version, {}", &ver);
+                self.remote_version = ver;
+                Lookup::Done
+            }
+            Err(e) => {
+                debug!("Remote lookup failed, {:?}", e);
+                self.remote_version = self.version.clone();
+                Lookup::Failed(e.to_string())
+            }
+        };
+    }
+
+    /// Uses a fresh cache entry if there is one, the remote otherwise.
+    /// The offline mode has its own source and bypasses the cache.
+    fn cached_lookup(&mut self, cfg: &Config, cache: &mut Cache) -> Result<Version> {
+        let key = match Cache::key(&self.name, &self.source) {
+            Some(key) if !cfg.offline => key,
+            _ => return self.remote_lookup(cfg),
+        };
+        if !cfg.refresh && let Some(entry) = cache.get(&key) {
+            debug!("Cached version, {:?}", entry);
+            if let (Git{remote_commit, ..}, Some(co)) = (&mut self.source, entry.commit) {
+                *remote_commit = co;
+            }
+            self.cache_age = Some(entry.age);
+            self.remote_bins = entry.bins;
+            self.remote_features = entry.features;
+            return Ok(entry.version);
+        }
+        let ver = self.remote_lookup(cfg)?;
+        let commit = match self.source {
+            Git{ref remote_commit, ..} => Some(remote_commit.clone()),
+            _ => None,
+        };
+        cache.insert(key, &Entry {
+            version: ver.clone(),
+            commit,
+            bins: self.remote_bins.clone(),
+            features: self.remote_features.clone(),
+            age: Duration::ZERO,
+        });
+        Ok(ver)
+    }
+
+    fn remote_lookup(&mut self, cfg: &Config) -> Result<Version> {
         let ver = match self.source {
-            CratesIo => {
-                let tmp: &str = self.name.as_ref();
-                match parse_cratesio(tmp) {
-                    Ok(out) => out,
-                    Err(e) => {
-                        println!("{} {}", e, self);
-                        format!{"{}", self.version}
-                    }
-                }
+            CratesIo if cfg.offline => {
+                let pre = !self.version.pre.is_empty();
+                let (ver, age) = index::latest_version(&cfg.cpath, &self.name, pre)?;
+                self.index_age = Some(age);
+                return Ok(ver);
+            }
+            CratesIo => parse_cratesio(self.name.as_str(), cfg)?,
+            Git{..} if cfg.offline => {
+                return Err(UpgradeError::from("git sources can not be checked offline"));
             }
             Git{ref url, ref mut remote_commit, ..} => {
-                match TempDir::new("tmprepo") {
-                    Ok(tmpd) => {
-                        let reppath = tmpd.path().to_path_buf();
-                        cmd_run(&["git", "clone", "--depth=1", url, reppath.to_str().unwrap()], cfg.verbose);
-                        let out = match parse_cargo_toml(&reppath, "version") {
-                            Ok(out) => out,
-                            Err(e) => {
-                                println!("{}", e);
-                                format!{"{}", self.version}
-                            }
-                        };
-                        let ncommit = cmd_return(&["git", "ls-remote", reppath.to_str().unwrap(), "HEAD"]);
+                let tmpd = TempDir::new("tmprepo")?;
+                let reppath = tmpd.path().to_str().unwrap();
+                cmd_output(&["git", "clone", "--depth=1", url, reppath], cfg.lookup_timeout)?;
+                let manifest = find_package(tmpd.path(), &self.name)?;
+                let ncommit = cmd_return(&["git", "ls-remote", reppath, "HEAD"], cfg.lookup_timeout);
+                match ncommit.split_whitespace().next() {
+                    Some(co) => {
                         remote_commit.clear();
-                        remote_commit.push_str(&ncommit[..41]);
-                        out
-                    }
-                    Err(e) => {
-                        println!("Could not crate tempdir {}", e);
-                        format!{"{}", self.version}
+                        remote_commit.push_str(co);
                     }
+                    None => return Err(UpgradeError::Parse(String::from("git ls-remote"))),
                 }
+                self.remote_bins = Some(manifest.bins);
+                self.remote_features = Some(manifest.features);
+                return Ok(manifest.version);
             }
             Local{ ref path } => {
-                match parse_cargo_toml(path, "version") {
-                    Ok(out) => out,
+                if cfg.pull && !cfg.offline {
+                    pull_local(path, cfg)?;
+                }
+                let manifest = find_package(path, &self.name)?;
+                self.remote_bins = Some(manifest.bins);
+                self.remote_features = Some(manifest.features);
+                return Ok(manifest.version);
+            }
+        };
+        Version::parse(&ver).map_err(|e| UpgradeError::Parse(format!("version {}: {}", ver, e)))
+    }
+
+    /// Compares git commits and local sources with the state of the installation.
+    /// Needs a finished remote lookup. The first check of a local source records its
+    /// fingerprint as the baseline for the next checks.
+    pub fn check_sources(&mut self, state: &mut State, cfg: &Config) {
+        if self.lookup != Lookup::Done {
+            return;
+        }
+        self.sources_changed = match self.source {
+            CratesIo => None,
+            Git{ref commit, ref remote_commit, ..} if !remote_commit.is_empty() => {
+                Some(commit != remote_commit)
+            }
+            Git{..} => None,
+            Local{ref path} => {
+                match fingerprint(path, cfg.lookup_timeout) {
+                    Ok(fp) => {
+                        debug!("{} fingerprint {}", self.name, fp);
+                        let changed = state.fingerprint(&self.name).map(|old| old != fp);
+                        if changed.is_none() {
+                            state.set_fingerprint(&self.name, &fp);
+                        }
+                        self.fingerprint = Some(fp);
+                        changed
+                    }
                     Err(e) => {
-                        println!("{}. Unable to extract version information", e);
-                        format!{"{}", self.version}
+                        debug!("{} fingerprint failed {}", self.name, e);
+                        None
                     }
                 }
             }
         };
-        debug!("Remote version, {}", &ver);
-        self.remote_version = Version::parse(&ver).unwrap();
     }
 
     /// Upgrade package
-    pub fn upgrade(&self, cfg: &Config) {
-        println!("Update {}", self);
-        if cfg.upgrade {
-            if let Ok(ba) = self.backup(cfg) {
-                self.uninstall();
-                let success = self.install();
-                if !success {
+    pub fn upgrade(&self, cfg: &Config, progress: &Progress) -> Outcome {
+        if !cfg.upgrade {
+            println!("Update {}{}", self, self.age_note());
+            return Outcome::Upgraded;
+        }
+        let eta = progress.remaining().map(|r| format!(" (about {} left)", fmt_age(r))).unwrap_or_default();
+        println!("[{}] Upgrading {} {} -> {}{}{}",
+                 progress.position(), self.name, self.version, self.remote_version, self.age_note(), eta);
+        self.start_log(cfg);
+        if !run_hooks(cfg, self, Stage::PreUpgrade) {
+            println!("Skipping {}", self.name);
+            return Outcome::Skipped;
+        }
+        progress.phase("backing up");
+        // a signal during the backup must not leave a backup without journal behind
+        set_critical(true);
+        match self.backup(cfg) {
+            Ok(ba) => {
+                let mut journal = match Journal::begin(cfg, self, ba.path()) {
+                    Ok(journal) => journal,
+                    Err(e) => {
+                        set_critical(false);
+                        println!("Could not write journal: {}", e);
+                        return Outcome::Failed;
+                    }
+                };
+                if interrupted() {
+                    journal.finish();
+                    drop(ba);
+                    println!("Interrupted before upgrading {}", self.name);
+                    std::process::exit(130);
+                }
+                progress.phase("building");
+                self.uninstall(cfg);
+                let _ = journal.set_phase("installing");
+                let installed = !interrupted() && self.install(cfg);
+                let mut success = installed;
+                if success && !interrupted() && self.verify_enabled(cfg) {
+                    let _ = journal.set_phase("verifying");
+                    progress.phase("verifying");
+                    success = self.verify(cfg);
+                }
+                if success {
+                    println!("Upgraded {} to {} in {}", self.name, self.remote_version, fmt_age(progress.elapsed()));
+                } else {
                     println!("Update not successful. Use backup");
-                    self.reverse_backup(ba, cfg);
+                    // binaries only the new version has are not replaced by the backup
+                    let added = if installed { self.added_binaries(cfg) } else { Vec::new() };
+                    self.reverse_backup(&ba, cfg);
+                    self.remove_binaries(&added, cfg);
                 }
+                journal.finish();
+                if interrupted() {
+                    drop(ba);
+                    match success {
+                        true => println!("Interrupted after upgrading {}", self.name),
+                        false => println!("Interrupted. Restored {} {}", self.name, self.version),
+                    }
+                    std::process::exit(130);
+                }
+                set_critical(false);
+                if !success {
+                    return Outcome::Failed;
+                }
+                run_hooks(cfg, self, Stage::PostUpgrade);
+                Outcome::Upgraded
+            }
+            Err(e) => {
+                set_critical(false);
+                println!("Could not create backup: {}", e);
+                if interrupted() {
+                    std::process::exit(130);
+                }
+                Outcome::Failed
             }
         }
     }
 
-    fn uninstall(&self) {
+    fn verify_enabled(&self, cfg: &Config) -> bool {
+        cfg.verify || cfg.settings.verify || self.test_command(cfg).is_some()
+    }
+
+    fn test_command<'a>(&self, cfg: &'a Config) -> Option<&'a str> {
+        cfg.settings.for_crate(&self.name)?.test.as_deref()
+    }
+
+    /// Smoke test of the installed binaries. Runs the configured test command of the crate
+    /// or each binary of the new version with `--version`. Fails if one of them is missing.
+    fn verify(&self, cfg: &Config) -> bool {
+        info!("Verify {}", self.name);
+        if let Some(cmd) = self.test_command(cfg) {
+            let ok = run_shell(cmd, self, cfg);
+            if !ok {
+                println!("Test of {} failed: {}", self.name, cmd);
+            }
+            return ok;
+        }
+        for binary in self.new_binaries(cfg) {
+            if !binary.exists() {
+                println!("Verification of {} failed: {} was not installed", self.name, binary.display());
+                return false;
+            }
+            let bin = binary.to_str().unwrap();
+            match cmd_run(&[bin, "--version"], cfg.verbose, cfg.hook_timeout) {
+                Ok(true) => {},
+                Ok(false) => {
+                    println!("Verification of {} failed: {} --version", self.name, bin);
+                    return false;
+                }
+                Err(e) => {
+                    println!("Verification of {} failed: {}", self.name, e);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Binaries the new version installs. Taken from its bin targets if they were looked up,
+    /// or else from what cargo recorded for the installation.
+    fn new_binaries(&self, cfg: &Config) -> Vec<PathBuf> {
+        match self.remote_bins {
+            Some(ref bins) => bins.iter().map(|b| cfg.cpath.join("bin").join(format!("{}{}", b, EXE_SUFFIX))).collect(),
+            None => read_installed_packages(cfg).unwrap_or_default()
+                .into_iter()
+                .find(|cv| cv.name == self.name)
+                .map(|cv| cv.binaries)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Binaries of the new version the old one did not have
+    fn added_binaries(&self, cfg: &Config) -> Vec<PathBuf> {
+        let old: Vec<_> = self.binaries.iter().filter_map(|b| b.file_name()).collect();
+        self.new_binaries(cfg).into_iter()
+            .filter(|b| b.file_name().is_some_and(|f| !old.contains(&f)))
+            .collect()
+    }
+
+    /// Removes binaries left behind by a rolled back version, unless another crate records them
+    fn remove_binaries(&self, binaries: &[PathBuf], cfg: &Config) {
+        let installed = read_installed_packages(cfg).unwrap_or_default();
+        for binary in binaries {
+            if installed.iter().any(|cv| cv.binaries.contains(binary)) {
+                continue;
+            }
+            if let Err(e) = remove_file(binary) && e.kind() != std::io::ErrorKind::NotFound {
+                println!("Could not remove {}: {}", binary.display(), e);
+            }
+        }
+    }
+
+    fn uninstall(&self, cfg: &Config) {
         info!("Uninstall {}", self.name);
-        cmd_run(&["cargo", "uninstall", &self.name], true);
+        // failures show up in the following installation
+        let _ = cmd_log(&["cargo", "uninstall", &self.name], &self.log_path(cfg), cfg.verbose, cfg.lookup_timeout);
+    }
+
+    /// Build log of the last upgrade
+    pub fn log_path(&self, cfg: &Config) -> PathBuf {
+        cfg.cpath.join(LOG_DIR).join(format!("{}.log", self.name))
+    }
+
+    /// Replaces the log of the previous upgrade
+    fn start_log(&self, cfg: &Config) {
+        let log = self.log_path(cfg);
+        if let Err(e) = create_dir_all(cfg.cpath.join(LOG_DIR)).and_then(|_| File::create(&log)) {
+            println!("Could not create log {}: {}", log.display(), e);
+        }
+    }
+
+    /// Shows the end of the build log
+    fn show_log(&self, cfg: &Config) {
+        let log = self.log_path(cfg);
+        println!("Last lines of {}:", log.display());
+        for line in tail(&log, cfg.log_lines) {
+            println!("    {}", line);
+        }
+    }
+
+    /// Installs the newest version
+    pub fn install(&self, cfg: &Config) -> bool {
+        self.cargo_install(cfg, false)
+    }
+
+    /// Installs the recorded version again, e.g. to restore deleted binaries.
+    /// Local sources are installed as they are.
+    pub fn reinstall(&self, cfg: &Config) -> bool {
+        self.start_log(cfg);
+        self.cargo_install(cfg, true)
     }
 
-    fn install(&self) -> bool {
+    fn cargo_install(&self, cfg: &Config, pinned: bool) -> bool {
         info!("Install {}", self.name);
+        let pkgdir: PathBuf;
+        let version = format!("={}", self.version);
         let mut args = match self.source {
+            CratesIo if pinned => vec!["cargo", "install", &self.name, "--version", &version],
             CratesIo => vec!["cargo", "install", &self.name],
-            Git{ref url, ..} => vec!["cargo", "install", "--git", &url],
-            Local{ref path} => vec!["cargo", "install", "--path", path.to_str().unwrap()],
+            // the commit replaces the branch or tag recorded in the query
+            Git{ref url, ref commit, ..} if pinned => {
+                vec!["cargo", "install", "--git", url.split('?').next().unwrap_or(url), "--rev", commit, &self.name]
+            }
+            Git{ref url, ..} => vec!["cargo", "install", "--git", url, &self.name],
+            Local{ref path} => {
+                // the recorded path may be the workspace root instead of the package
+                pkgdir = find_package(path, &self.name).map_or_else(|_| path.clone(), |m| m.dir);
+                vec!["cargo", "install", "--path", pkgdir.to_str().unwrap()]
+            }
         };
+        if cfg.offline {
+            args.push("--offline");
+        }
+        if cfg.allow_conflicts || pinned {
+            // allows cargo to replace binaries of other crates and to install the same version again
+            args.push("--force");
+        }
 
+        if !self.features_known {
+            println!("The features of {} are unknown, installing it with the default features", self.name);
+        }
         let str: String;
         if !self.features.is_empty() {
             args.push("--features");
@@ -175,11 +563,36 @@ impl CrateVersion {
             debug!("feat {:?}", str);
             args.push(str.as_str());
         }
-        cmd_run(&args, true)
+        let log = self.log_path(cfg);
+        let res = retry(cfg, &format!("Installation of {}", self.name), || {
+            let start = metadata(&log).map_or(0, |m| m.len() as usize);
+            if cmd_log(&args, &log, cfg.verbose, cfg.build_timeout)? {
+                return Ok(());
+            }
+            // only the output of this attempt tells why it failed
+            let output = read(&log).ok()
+                .and_then(|o| o.get(start..).map(|o| String::from_utf8_lossy(o).into_owned()))
+                .unwrap_or_default();
+            Err(classify(&output, String::from("cargo install exited with an error")))
+        });
+        match res {
+            Ok(()) => true,
+            Err(e) => {
+                if !interrupted() {
+                    println!("Could not install {}: {}", self.name, e);
+                    if !cfg.verbose {
+                        self.show_log(cfg);
+                    }
+                }
+                false
+            }
+        }
     }
 
     fn backup(&self, cfg: &Config) -> Result<TempDir> {
-        let tmpd = TempDir::new(&self.name)?;
+        let backupdir = cfg.cpath.join(BACKUP_DIR);
+        DirBuilder::new().recursive(true).create(&backupdir)?;
+        let tmpd = TempDir::new_in(backupdir, &self.name)?;
         let mut reppath = tmpd.path().to_path_buf();
         reppath.push("bin");
         DirBuilder::new()
@@ -210,7 +623,7 @@ impl CrateVersion {
         Ok(tmpd)
     }
 
-    fn reverse_backup<P>(&self, dir: P, cfg: &Config) where P: AsRef<Path> {
+    pub fn reverse_backup<P>(&self, dir: P, cfg: &Config) where P: AsRef<Path> {
         let mut tmppath = dir.as_ref().to_path_buf();
         let mut cargopath = cfg.cpath.clone();
         tmppath.push("bin");
@@ -236,12 +649,21 @@ impl CrateVersion {
         cargopath.pop();
         tmppath.push(".crates2.json");
         cargopath.push(".crates2.json");
-        let _ = copy(&tmppath, &cargopath);
+        if tmppath.exists() {
+            let _ = copy(&tmppath, &cargopath);
+        } else {
+            // the root only had `.crates.toml`, the one cargo created would contradict it
+            let _ = remove_file(&cargopath);
+        }
     }
 }
 
 impl fmt::Display for CrateVersion {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let remote_version = match self.lookup {
+            Lookup::Failed(_) => String::from("?"),
+            _ => self.remote_version.to_string(),
+        };
         match self.source {
             CratesIo => {
                 write!(f,
@@ -249,7 +671,7 @@ impl fmt::Display for CrateVersion {
                        self.name,
                        self.features.join(","),
                        self.version,
-                       self.remote_version)
+                       remote_version)
             }
             Git{ref url, ref commit, ref remote_commit} => {
                 write!(f,
@@ -258,7 +680,7 @@ impl fmt::Display for CrateVersion {
                        self.features.join(","),
                        self.version,
                        commit,
-                       self.remote_version,
+                       remote_version,
                        remote_commit,
                        url)
             }
@@ -268,20 +690,20 @@ impl fmt::Display for CrateVersion {
                        self.name,
                        self.features.join(","),
                        self.version,
-                       self.remote_version,
+                       remote_version,
                        p.display())
             }
         }
     }
 }
 
-fn parse_cratesio<'a, S>(cratename: S) -> Result<String> where S: Into<Cow<'a,str>>{
+fn parse_cratesio<'a, S>(cratename: S, cfg: &Config) -> Result<String> where S: Into<Cow<'a,str>>{
     let cratename = cratename.into();
-    let input = cmd_return(&["cargo", "search", cratename.as_ref()]);
+    let input = cmd_output(&["cargo", "search", cratename.as_ref()], cfg.lookup_timeout)?;
     let line = match input.lines()
             .find(|x| x.starts_with(&format!("{} ", cratename))) {
         Some(line) => line,
-        None => return Err(UpgradeError::NoCrate(cratename.into_owned())),
+        None => return Err(UpgradeError::NoCrate(format!("{} not found on crates.io", cratename))),
     };
     match line.split('"').map(|s| s.trim()).nth(1) {
         Some(val) => Ok(val.to_owned()),
@@ -289,28 +711,66 @@ fn parse_cratesio<'a, S>(cratename: S) -> Result<String> where S: Into<Cow<'a,st
     }
 }
 
-fn parse_cargo_toml<P,S>(path: P, field: S) -> Result<String>
-                where P: AsRef<Path>, S: AsRef<str> {
-    use serde_json::Value;
-    let pa: PathBuf = path.as_ref().join("Cargo.toml");
-    if !pa.is_file() {
-        return Err(UpgradeError::Io(
-                Error::new(ErrorKind::NotFound, pa.to_str().unwrap().to_owned())))
+/// Fast-forwards a local git checkout to its upstream.
+/// Paths outside of a git work tree are left alone.
+fn pull_local(path: &Path, cfg: &Config) -> Result<()> {
+    let dir = path.to_str().unwrap();
+    if cmd_return(&["git", "-C", dir, "rev-parse", "--is-inside-work-tree"], cfg.lookup_timeout).trim() != "true" {
+        debug!("{} is not a git work tree", dir);
+        return Ok(());
+    }
+    let changes = cmd_return(&["git", "-C", dir, "status", "--porcelain", "--untracked-files=no"], cfg.lookup_timeout);
+    if !changes.trim().is_empty() {
+        return Err(UpgradeError::Gen(format!("{} has uncommitted changes, refusing to pull", dir)));
+    }
+    cmd_output(&["git", "-C", dir, "fetch"], cfg.lookup_timeout)?;
+    if !cmd_run(&["git", "-C", dir, "merge", "--ff-only", "@{upstream}"], cfg.verbose, cfg.lookup_timeout)? {
+        return Err(UpgradeError::Gen(format!("could not fast-forward {} to its upstream", dir)));
     }
-    let pa = pa.to_str().unwrap();
+    Ok(())
+}
 
-    //let pa = format!("{}/Cargo.toml", path);
-    let input = cmd_return(&["cargo", "read-manifest", "--manifest-path", pa]);
-    trace!("{}", &input);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
 
-    let val: Value = serde_json::from_str(&input).unwrap_or_else(|e| panic!("open {}", e));
-    let obj = val.as_object().unwrap();
-    trace!("{:?}", obj);
+    /// A cargo home after an upgrade of `foo` and the backup taken before it
+    fn upgraded(backup_v2: bool) -> (TempDir, PathBuf, CrateVersion) {
+        let tmp = TempDir::new("home").unwrap();
+        let home = tmp.path();
+        let backup = home.join("backup");
+        for dir in [home.join("bin"), backup.join("bin")] {
+            create_dir_all(dir).unwrap();
+        }
+        write(home.join("bin").join("foo"), "new").unwrap();
+        write(home.join(".crates.toml"), "new").unwrap();
+        write(home.join(".crates2.json"), "new").unwrap();
+        write(backup.join("bin").join("foo"), "old").unwrap();
+        write(backup.join(".crates.toml"), "old").unwrap();
+        if backup_v2 {
+            write(backup.join(".crates2.json"), "old").unwrap();
+        }
+        let mut cv = CrateVersion::new("foo", Version::new(1, 0, 0));
+        cv.set_binaries(&[home.join("bin").join("foo")]);
+        (tmp, backup, cv)
+    }
+
+    #[test]
+    fn reverse_backup_restores_both_files() {
+        let (tmp, backup, cv) = upgraded(true);
+        cv.reverse_backup(&backup, &Config::for_test(tmp.path()));
+        for file in ["bin/foo", ".crates.toml", ".crates2.json"] {
+            assert_eq!(read_to_string(tmp.path().join(file)).unwrap(), "old", "{}", file);
+        }
+    }
 
-    let verstr = obj.get(field.as_ref()).unwrap();
-    if let Value::String(ref v) = *verstr {
-        debug!("Version: {}", v);
-        return Ok(v.to_owned());
+    #[test]
+    fn reverse_backup_removes_the_crates2_json_cargo_created() {
+        let (tmp, backup, cv) = upgraded(false);
+        cv.reverse_backup(&backup, &Config::for_test(tmp.path()));
+        assert_eq!(read_to_string(tmp.path().join("bin/foo")).unwrap(), "old");
+        assert_eq!(read_to_string(tmp.path().join(".crates.toml")).unwrap(), "old");
+        assert!(!tmp.path().join(".crates2.json").exists());
     }
-    Err(UpgradeError::Parse(String::from("Cargo.toml")))
 }
@@ -0,0 +1,334 @@
+use serde_json::{Map, Value};
+use toml::Table;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read_to_string;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::crateversion::Result;
+use crate::error::UpgradeError;
+use crate::metadata::{read_v1, read_v2, PackageId, Source, CRATES_V1, CRATES_V2};
+use crate::util::write_atomic;
+use crate::EXIT_FAILED;
+
+/// A problem in the install metadata and how to solve it
+struct Finding {
+    problem: String,
+    fix: String,
+    /// Set if the fix only restores what the other file records
+    repair: Option<Repair>,
+}
+
+/// Fixes that `--fix` applies
+enum Repair {
+    /// Records the binaries in `.crates.toml`
+    SetV1(String, Vec<String>),
+}
+
+/// Validates `.crates.toml` and `.crates2.json` and reports each finding with a suggested fix.
+/// With `fix` the safe fixes are applied.
+pub fn run(cfg: &Config, fix: bool) -> i32 {
+    let findings = diagnose(cfg);
+    if findings.is_empty() {
+        println!("No problems found in {} and {}.", CRATES_V1, CRATES_V2);
+        return 0;
+    }
+    for finding in &findings {
+        println!("{}", finding.problem);
+        let safe = if finding.repair.is_some() { " (applied by --fix)" } else { "" };
+        println!("    fix: {}{}", finding.fix, safe);
+    }
+
+    let repairs: Vec<&Repair> = findings.iter().filter_map(|f| f.repair.as_ref()).collect();
+    if !fix || repairs.is_empty() {
+        return EXIT_FAILED;
+    }
+    if let Err(e) = apply(cfg, &repairs) {
+        eprintln!("Could not apply the fixes: {}", e);
+        return EXIT_FAILED;
+    }
+    println!("Fixed {} of {} problem(s).", repairs.len(), findings.len());
+    if repairs.len() == findings.len() { 0 } else { EXIT_FAILED }
+}
+
+fn diagnose(cfg: &Config) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let v2 = match read_v2(&cfg.cpath) {
+        Ok(v2) => Some(v2),
+        Err(e) => {
+            findings.push(unreadable(CRATES_V2, e));
+            None
+        }
+    };
+    let v1 = match read_v1(&cfg.cpath) {
+        Ok(v1) => Some(v1),
+        Err(e) => {
+            findings.push(unreadable(CRATES_V1, e));
+            None
+        }
+    };
+
+    let keys = v2.iter().flat_map(|m| m.keys()).chain(v1.iter().flat_map(|t| t.keys()));
+    for key in keys.collect::<BTreeSet<_>>() {
+        let id = match PackageId::parse(key) {
+            Ok(id) => id,
+            Err(e) => {
+                findings.push(Finding {
+                    problem: e.to_string(),
+                    fix: format!("remove the entry from {} and {} and install the crate again", CRATES_V1, CRATES_V2),
+                    repair: None,
+                });
+                continue;
+            }
+        };
+        if let Err(e) = id.crate_version() {
+            findings.push(Finding {
+                problem: e.to_string(),
+                fix: format!("run `cargo install --force {}` to record a valid version", id.name),
+                repair: None,
+            });
+        }
+        if let Source::Path(ref path) = id.source
+            && !path.exists()
+        {
+            findings.push(Finding {
+                problem: format!("Source of {} no longer exists: {}", id, path.display()),
+                fix: format!("install {} from crates.io or git, or run `cargo uninstall {}`", id.name, id.name),
+                repair: None,
+            });
+        }
+    }
+    let v2_names = v2.as_ref().map(|m| by_name(m.keys())).unwrap_or_default();
+    let v1_names = v1.as_ref().map(|t| by_name(t.keys())).unwrap_or_default();
+    for (file, names) in [(CRATES_V2, &v2_names), (CRATES_V1, &v1_names)] {
+        for (name, keys) in names.iter().filter(|(_, keys)| keys.len() > 1) {
+            findings.push(Finding {
+                problem: format!("{} records {} {} times: {}", file, name, keys.len(), keys.join(", ")),
+                fix: format!("run `cargo uninstall {}` and install it again", name),
+                repair: None,
+            });
+        }
+    }
+
+    if let (Some(v2), Some(v1)) = (v2, v1) {
+        cross_check(&v2, &v1, &v2_names, &v1_names, &cfg.cpath.join("bin"), &mut findings);
+    }
+    findings
+}
+
+/// Valid package ids by crate name
+fn by_name<'a>(keys: impl Iterator<Item = &'a String>) -> BTreeMap<String, Vec<String>> {
+    let mut names: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for key in keys {
+        if let Ok(id) = PackageId::parse(key) {
+            names.entry(id.name).or_default().push(key.clone());
+        }
+    }
+    names
+}
+
+/// Finds entries the two files disagree on, invalid ones are reported by `diagnose`.
+/// The binaries of `.crates2.json` are only copied to `.crates.toml` if they exist in `bin_dir` and
+/// `.crates.toml` records no other version of the crate. `.crates.toml` lacks features and build
+/// settings, so entries missing from `.crates2.json` are never restored.
+fn cross_check(v2: &Map<String, Value>, v1: &Table, v2_names: &BTreeMap<String, Vec<String>>,
+               v1_names: &BTreeMap<String, Vec<String>>, bin_dir: &Path, findings: &mut Vec<Finding>) {
+    let other = |names: &BTreeMap<String, Vec<String>>, key: &str| {
+        PackageId::parse(key).ok().and_then(|id| names.get(&id.name).cloned())
+    };
+    for (key, details) in v2.iter().filter(|(key, _)| PackageId::parse(key).is_ok()) {
+        let bins2 = match details.get("bins").and_then(Value::as_array) {
+            Some(bins) => bins.iter().filter_map(Value::as_str).map(String::from).collect::<Vec<_>>(),
+            None => {
+                findings.push(Finding {
+                    problem: format!("{} records no binaries for {}", CRATES_V2, key),
+                    fix: "install the crate again with `cargo install --force`".to_owned(),
+                    repair: None,
+                });
+                continue;
+            }
+        };
+        let bins1 = v1.get(key).map(|bins| {
+            bins.as_array().into_iter().flatten()
+                .filter_map(toml::Value::as_str).map(String::from).collect::<BTreeSet<_>>()
+        });
+        let problem = match bins1 {
+            None => {
+                if let Some(others) = other(v1_names, key) {
+                    findings.push(disagreement(key, &others));
+                    continue;
+                }
+                format!("{} has no entry for {}", CRATES_V1, key)
+            }
+            Some(ref bins1) if *bins1 != bins2.iter().cloned().collect() =>
+                format!("{} and {} record different binaries for {}", CRATES_V1, CRATES_V2, key),
+            Some(_) => continue,
+        };
+        let missing: Vec<&String> = bins2.iter().filter(|b| !bin_dir.join(b).exists()).collect();
+        if missing.is_empty() {
+            findings.push(Finding {
+                problem,
+                fix: format!("record the binaries of {} in {}", CRATES_V2, CRATES_V1),
+                repair: Some(Repair::SetV1(key.clone(), bins2)),
+            });
+        } else {
+            let missing: Vec<&str> = missing.iter().map(|b| b.as_str()).collect();
+            findings.push(Finding {
+                problem: format!("{}, binaries missing from {}: {}", problem, bin_dir.display(), missing.join(", ")),
+                fix: "run `cargo install-upgrade bins --repair` to install them again".to_owned(),
+                repair: None,
+            });
+        }
+    }
+    for key in v1.keys().filter(|key| !v2.contains_key(*key) && PackageId::parse(key).is_ok()) {
+        // Reported above as a disagreement or a duplicate
+        if other(v2_names, key).is_some() {
+            continue;
+        }
+        let name = PackageId::parse(key).map(|id| id.name).unwrap_or_default();
+        findings.push(Finding {
+            problem: format!("{} has no entry for {}", CRATES_V2, key),
+            fix: format!("run `cargo install --force {}` to record its features and profile, {} does not know them",
+                         name, CRATES_V1),
+            repair: None,
+        });
+    }
+}
+
+/// The files record different versions or sources of a crate
+fn disagreement(v2_key: &str, v1_keys: &[String]) -> Finding {
+    let name = PackageId::parse(v2_key).map(|id| id.name).unwrap_or_default();
+    Finding {
+        problem: format!("{} records {}, {} records {}", CRATES_V2, v2_key, CRATES_V1, v1_keys.join(", ")),
+        fix: format!("run `cargo install --force {}` to record the installed version in both", name),
+        repair: None,
+    }
+}
+
+fn unreadable(file: &str, e: UpgradeError) -> Finding {
+    Finding {
+        problem: format!("Could not read {}: {}", file, e),
+        fix: format!("restore {} from a backup or install any crate to let cargo rewrite it", file),
+        repair: None,
+    }
+}
+
+/// Edits `.crates.toml` and keeps everything the repairs do not touch
+fn apply(cfg: &Config, repairs: &[&Repair]) -> Result<()> {
+    let v1_path = cfg.cpath.join(CRATES_V1);
+    let mut v1: Table = read_to_string(&v1_path)?.parse()?;
+    let v1_entries = v1.get_mut("v1").and_then(toml::Value::as_table_mut)
+        .ok_or_else(|| UpgradeError::Parse(format!("{}: no v1 table", CRATES_V1)))?;
+    for repair in repairs {
+        match **repair {
+            Repair::SetV1(ref key, ref bins) => {
+                let bins = bins.iter().map(|b| toml::Value::String(b.clone())).collect();
+                v1_entries.insert(key.clone(), toml::Value::Array(bins));
+            }
+        }
+    }
+    write_atomic(&v1_path, &v1.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    const REG: &str = "(registry+https://github.com/rust-lang/crates.io-index)";
+
+    fn check(v2: Value, v1: &str, installed: &[&str]) -> Vec<Finding> {
+        let Value::Object(v2) = v2 else { panic!("v2 is no object") };
+        let v1: Table = v1.parse().unwrap();
+        let bin_dir = TempDir::new("doctor").unwrap();
+        for bin in installed {
+            write(bin_dir.path().join(bin), "").unwrap();
+        }
+        let mut findings = Vec::new();
+        cross_check(&v2, &v1, &by_name(v2.keys()), &by_name(v1.keys()), bin_dir.path(), &mut findings);
+        findings
+    }
+
+    fn key(name: &str, version: &str) -> String {
+        format!("{} {} {}", name, version, REG)
+    }
+
+    #[test]
+    fn empty_files_agree() {
+        assert!(check(json!({}), "", &[]).is_empty());
+    }
+
+    #[test]
+    fn matching_entries() {
+        let findings = check(
+            json!({ key("ripgrep", "14.1.0"): { "bins": ["rg"] } }),
+            &format!("\"{}\" = [\"rg\"]", key("ripgrep", "14.1.0")),
+            &["rg"],
+        );
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn missing_v1_entry_is_restored_if_the_binaries_exist() {
+        let findings = check(json!({ key("ripgrep", "14.1.0"): { "bins": ["rg"] } }), "", &["rg"]);
+        assert_eq!(findings.len(), 1);
+        assert!(matches!(findings[0].repair, Some(Repair::SetV1(ref k, ref bins)) if *k == key("ripgrep", "14.1.0") && bins == &["rg"]));
+
+        let findings = check(json!({ key("ripgrep", "14.1.0"): { "bins": ["rg"] } }), "", &[]);
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].repair.is_none());
+        assert!(findings[0].problem.ends_with(": rg"));
+    }
+
+    #[test]
+    fn different_binaries() {
+        let findings = check(
+            json!({ key("cargo-edit", "0.13.0"): { "bins": ["cargo-add", "cargo-rm"] } }),
+            &format!("\"{}\" = [\"cargo-add\"]", key("cargo-edit", "0.13.0")),
+            &["cargo-add", "cargo-rm"],
+        );
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].problem.contains("record different binaries"));
+        assert!(findings[0].repair.is_some());
+    }
+
+    #[test]
+    fn disagreeing_versions_are_not_repaired() {
+        let findings = check(
+            json!({ "lc 0.6.0 (path+file:///tmp/lc)": { "bins": ["lc"] } }),
+            "\"lc 0.7.0 (path+file:///tmp/lc)\" = [\"lc\"]",
+            &["lc"],
+        );
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].problem.contains("lc 0.7.0"));
+        assert!(findings[0].repair.is_none());
+    }
+
+    #[test]
+    fn missing_v2_entry_is_not_repaired() {
+        let git = "foo 0.1.0 (git+https://example.com/foo#0123abc)";
+        let findings = check(json!({}), &format!("\"{}\" = [\"foo\"]", git), &["foo"]);
+        assert_eq!(findings.len(), 1);
+        assert!(findings[0].problem.contains(CRATES_V2));
+        assert!(findings[0].repair.is_none());
+    }
+
+    #[test]
+    fn malformed_entries() {
+        let findings = check(
+            json!({
+                "not a package id": { "bins": ["x"] },
+                key("nobins", "1.0.0"): {},
+                key("badbins", "1.0.0"): { "bins": "b" },
+            }),
+            "\"also not an id\" = [\"y\"]",
+            &[],
+        );
+        // invalid ids are reported by diagnose
+        assert_eq!(findings.len(), 2);
+        assert!(findings.iter().all(|f| f.problem.contains("records no binaries") && f.repair.is_none()));
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt::{Display, Formatter, Result};
 use std::error::Error;
 use std::io;
+use std::path::PathBuf;
 use self::UpgradeError::*;
 
 #[derive(Debug)]
@@ -8,8 +9,14 @@ pub enum UpgradeError {
     Parse(String),
     NoCrate(String),
     Gen(String),
+    /// A failure that may go away if the step is repeated
+    Transient(String),
+    /// A command was killed because it ran too long
+    Timeout(String),
     Io(io::Error),
     SerdeError(serde_json::Error),
+    Toml(toml::de::Error),
+    Manifest(PathBuf, String),
 }
 
 impl Display for UpgradeError {
@@ -18,8 +25,25 @@ impl Display for UpgradeError {
             Parse(ref s) => write!(f, "Parse Error {}", &s),
             NoCrate(ref s) => write!(f, "{}", &s),
             Gen(ref s) => write!(f, "{}", &s),
+            Transient(ref s) => write!(f, "{}", &s),
+            Timeout(ref s) => write!(f, "{}", &s),
             Io(ref err) => err.fmt(f),
             SerdeError(ref err) => err.fmt(f),
+            Toml(ref err) => err.fmt(f),
+            Manifest(ref path, ref s) => write!(f, "{}: {}", path.display(), &s),
+        }
+    }
+}
+
+impl UpgradeError {
+    /// True if repeating the failed step may succeed
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            Transient(_) => true,
+            Io(ref err) => matches!(err.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock),
+            _ => false,
         }
     }
 }
@@ -30,6 +54,7 @@ impl Error for UpgradeError {
         match *self {
             Io(ref err) => Some(err),
             SerdeError(ref err) => Some(err),
+            Toml(ref err) => Some(err),
             _ => None
         }
     }
@@ -52,3 +77,9 @@ impl From<serde_json::Error> for UpgradeError {
         UpgradeError::SerdeError(err)
     }
 }
+
+impl From<toml::de::Error> for UpgradeError {
+    fn from(err: toml::de::Error) -> UpgradeError {
+        UpgradeError::Toml(err)
+    }
+}
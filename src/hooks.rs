@@ -0,0 +1,65 @@
+use std::env::join_paths;
+use std::ffi::OsString;
+
+use crate::config::{Config, Hooks};
+use crate::crateversion::{CrateVersion, PackageSource};
+use crate::util::cmd_log_env;
+
+/// Point of the upgrade at which hooks run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    PreUpgrade,
+    PostUpgrade,
+}
+
+/// Runs the global and crate specific hooks of a stage.
+/// Pre-upgrade hooks run global first, post-upgrade hooks crate specific first.
+/// Returns false if a hook failed.
+pub fn run_hooks(cfg: &Config, cv: &CrateVersion, stage: Stage) -> bool {
+    let mut hooks: Vec<&Hooks> = vec![&cfg.settings.hooks];
+    if let Some(settings) = cfg.settings.for_crate(&cv.name) {
+        hooks.push(&settings.hooks);
+    }
+    if stage == Stage::PostUpgrade {
+        hooks.reverse();
+    }
+
+    for hook in hooks {
+        let (key, cmd) = match stage {
+            Stage::PreUpgrade => ("pre_upgrade", &hook.pre_upgrade),
+            Stage::PostUpgrade => ("post_upgrade", &hook.post_upgrade),
+        };
+        if let Some(cmd) = cmd && !run_shell(cmd, cv, cfg) {
+            println!("{} hook of {} failed: {}", key, cv.name, cmd);
+            return false;
+        }
+    }
+    true
+}
+
+/// Runs a user command through the shell with the crate described in the environment.
+/// The output goes to the terminal and the log of the crate. The command is killed
+/// after the hook timeout or when the run is interrupted.
+pub fn run_shell(cmd: &str, cv: &CrateVersion, cfg: &Config) -> bool {
+    info!("run shell command: {}", cmd);
+    let shell = if cfg!(target_os = "windows") { ["cmd", "/C"] } else { ["sh", "-c"] };
+    let source = match cv.source {
+        PackageSource::CratesIo => "registry",
+        PackageSource::Git { .. } => "git",
+        PackageSource::Local { .. } => "local",
+    };
+    let env = [
+        ("CARGO_INSTALL_UPGRADE_CRATE", OsString::from(&cv.name)),
+        ("CARGO_INSTALL_UPGRADE_OLD_VERSION", cv.version.to_string().into()),
+        ("CARGO_INSTALL_UPGRADE_NEW_VERSION", cv.remote_version.to_string().into()),
+        ("CARGO_INSTALL_UPGRADE_SOURCE", source.into()),
+        ("CARGO_INSTALL_UPGRADE_BINARIES", join_paths(&cv.binaries).unwrap_or_default()),
+    ];
+    match cmd_log_env(&[shell[0], shell[1], cmd], &env, &cv.log_path(cfg), true, cfg.hook_timeout) {
+        Ok(success) => success,
+        Err(e) => {
+            println!("Error running {}: {}", cmd, e);
+            false
+        }
+    }
+}
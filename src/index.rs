@@ -0,0 +1,190 @@
+use semver::Version;
+use serde_json::Value;
+
+use std::fs::{read, read_dir};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::crateversion::Result;
+use crate::error::UpgradeError;
+
+/// Index directories used by cargo for crates.io (sparse and git protocol)
+const CRATESIO_INDICES: [&str; 2] = ["index.crates.io-", "github.com-1ecc6299db9ec823"];
+
+/// Looks up the newest version of a crate in cargo's local registry index cache.
+/// Returns the version and the age of the cache entry it was read from.
+pub fn latest_version(cpath: &Path, name: &str, pre: bool) -> Result<(Version, Duration)> {
+    let file = find_cache_file(cpath, name)?;
+    let data = read(&file)?;
+    let age = file.metadata()?
+        .modified()
+        .ok()
+        .and_then(|m| SystemTime::now().duration_since(m).ok())
+        .unwrap_or_default();
+
+    let latest = parse_cache(&data)
+        .into_iter()
+        .map(|(v, _)| v)
+        .filter(|v| pre || v.pre.is_empty())
+        .max();
+    match latest {
+        Some(ver) => Ok((ver, age)),
+        None => Err(UpgradeError::Parse(format!("index cache {}", file.display()))),
+    }
+}
+
+/// All versions that are not yanked with their entry in the local registry index cache
+pub fn cached_versions(cpath: &Path, name: &str) -> Result<Vec<(Version, Value)>> {
+    let file = find_cache_file(cpath, name)?;
+    Ok(parse_cache(&read(&file)?))
+}
+
+/// Explicit features and implicit features of optional dependencies of an index entry
+pub fn features(entry: &Value) -> Vec<String> {
+    let explicit: Vec<(&String, &Value)> = ["features", "features2"].iter()
+        .filter_map(|k| entry[k].as_object())
+        .flatten()
+        .collect();
+    let mut features: Vec<String> = explicit.iter().map(|(k, _)| (*k).clone()).collect();
+    let uses_dep = |dep: &str| explicit.iter()
+        .filter_map(|(_, v)| v.as_array())
+        .flatten()
+        .any(|v| v.as_str() == Some(&format!("dep:{}", dep)));
+    for dep in entry["deps"].as_array().into_iter().flatten() {
+        if let (Some(true), Some(name)) = (dep["optional"].as_bool(), dep["name"].as_str())
+            && !uses_dep(name) && !features.iter().any(|f| f == name) {
+            features.push(name.to_owned());
+        }
+    }
+    features.sort();
+    features
+}
+
+/// Relative location of a crate inside the index, e.g. `se/mv/semver`
+pub fn index_path(name: &str) -> PathBuf {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => ["1", &name].iter().collect(),
+        2 => ["2", &name].iter().collect(),
+        3 => ["3", &name[..1], &name].iter().collect(),
+        _ => [&name[..2], &name[2..4], &name].iter().collect(),
+    }
+}
+
+/// Finds the most recently written cache entry for `name` in all crates.io indices
+fn find_cache_file(cpath: &Path, name: &str) -> Result<PathBuf> {
+    let mut indexdir = cpath.to_path_buf();
+    indexdir.push("registry");
+    indexdir.push("index");
+
+    let relpath = index_path(name);
+    let mut found: Option<(SystemTime, PathBuf)> = None;
+    for entry in read_dir(&indexdir)?.flatten() {
+        let dirname = entry.file_name();
+        let dirname = dirname.to_string_lossy();
+        if !CRATESIO_INDICES.iter().any(|p| dirname.starts_with(p)) {
+            continue;
+        }
+        let mut file = entry.path();
+        file.push(".cache");
+        file.push(&relpath);
+        if let Ok(modified) = file.metadata().and_then(|m| m.modified()) {
+            debug!("index cache: {}", file.display());
+            if found.as_ref().is_none_or(|(t, _)| *t < modified) {
+                found = Some((modified, file));
+            }
+        }
+    }
+    found.map(|(_, f)| f).ok_or_else(|| UpgradeError::NoCrate(format!("{} is not in the local index cache", name)))
+}
+
+/// Parses a cache file of the form
+/// `[cache version: u8][index version: u32][header]\0([version]\0[json]\0)*`
+/// and returns all versions that are not yanked with their index entry.
+fn parse_cache(data: &[u8]) -> Vec<(Version, Value)> {
+    let skip = match data.first() {
+        Some(1) => 1,
+        Some(_) => 5,
+        None => return Vec::new(),
+    };
+    let mut fields = data.get(skip..).unwrap_or_default().split(|b| *b == 0);
+    // index version (etag or last-modified header)
+    fields.next();
+
+    let mut versions = Vec::new();
+    while let (Some(ver), Some(json)) = (fields.next(), fields.next()) {
+        let Ok(ver) = std::str::from_utf8(ver) else { continue };
+        let Ok(ver) = Version::parse(ver) else { continue };
+        let Ok(json) = serde_json::from_slice::<Value>(json) else { continue };
+        if !json["yanked"].as_bool().unwrap_or(false) {
+            versions.push((ver, json));
+        }
+    }
+    versions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache file in the current format with the given versions and entries
+    fn cache(entries: &[(&str, &str)]) -> Vec<u8> {
+        let mut data = vec![3, 2, 0, 0, 0];
+        data.extend_from_slice(b"etag: \"abc\"\0");
+        for (ver, json) in entries {
+            data.extend_from_slice(ver.as_bytes());
+            data.push(0);
+            data.extend_from_slice(json.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    fn versions(data: &[u8]) -> Vec<String> {
+        parse_cache(data).into_iter().map(|(v, _)| v.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_cache_versions() {
+        let data = cache(&[
+            ("1.0.0", r#"{"name":"foo","vers":"1.0.0","yanked":false}"#),
+            ("1.1.0", r#"{"name":"foo","vers":"1.1.0","yanked":true}"#),
+            ("2.0.0-rc.1", r#"{"name":"foo","vers":"2.0.0-rc.1"}"#),
+        ]);
+        assert_eq!(versions(&data), ["1.0.0", "2.0.0-rc.1"]);
+        assert_eq!(parse_cache(&data)[0].1["name"], "foo");
+    }
+
+    #[test]
+    fn parse_cache_v1_header() {
+        let mut data = vec![1];
+        data.extend_from_slice(b"header\x001.0.0\0{\"vers\":\"1.0.0\"}\0");
+        assert_eq!(versions(&data), ["1.0.0"]);
+    }
+
+    #[test]
+    fn parse_cache_malformed() {
+        assert!(parse_cache(&[]).is_empty());
+        assert!(parse_cache(&[3, 2, 0]).is_empty());
+        assert!(parse_cache(&cache(&[])).is_empty());
+        let data = cache(&[
+            ("1.x", r#"{"vers":"1.x"}"#),
+            ("1.0.0", "{not json"),
+            ("1.0.1", r#"{"vers":"1.0.1"}"#),
+        ]);
+        assert_eq!(versions(&data), ["1.0.1"]);
+        // a truncated entry without json is dropped
+        let mut data = cache(&[("1.0.0", r#"{"vers":"1.0.0"}"#)]);
+        data.extend_from_slice(b"1.0.1");
+        assert_eq!(versions(&data), ["1.0.0"]);
+    }
+
+    #[test]
+    fn index_paths() {
+        assert_eq!(index_path("a"), Path::new("1/a"));
+        assert_eq!(index_path("cc"), Path::new("2/cc"));
+        assert_eq!(index_path("Syn"), Path::new("3/s/syn"));
+        assert_eq!(index_path("toml"), Path::new("to/ml/toml"));
+        assert_eq!(index_path("Serde_JSON"), Path::new("se/rd/serde_json"));
+    }
+}
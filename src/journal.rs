@@ -0,0 +1,321 @@
+use semver::Version;
+use serde_json::{json, Value};
+
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file};
+use std::io::{stdin, IsTerminal};
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, Recovery};
+use crate::crateversion::{CrateVersion, PackageSource, Result};
+use crate::error::UpgradeError;
+use crate::util::write_atomic;
+
+const JOURNAL_DIR: &str = ".install-upgrade-journal";
+/// Backups referenced by a journal have to survive a crash, so they are kept in the cargo home
+pub const BACKUP_DIR: &str = ".install-upgrade-backup";
+
+/// Record of an upgrade in progress. Removed once the upgrade is finished or rolled back.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    record: Value,
+}
+
+impl Journal {
+    /// Writes the journal for an upgrade of `cv` with the backup in `backup`
+    pub fn begin(cfg: &Config, cv: &CrateVersion, backup: &Path) -> Result<Journal> {
+        let dir = cfg.cpath.join(JOURNAL_DIR);
+        create_dir_all(&dir)?;
+        let source = match cv.source {
+            PackageSource::CratesIo => json!({ "kind": "registry" }),
+            PackageSource::Git { ref url, ref commit, ref remote_commit } => json!({
+                "kind": "git", "url": url, "commit": commit, "new_commit": remote_commit,
+            }),
+            PackageSource::Local { ref path } => json!({ "kind": "local", "path": path }),
+        };
+        let mut journal = Journal {
+            path: dir.join(format!("{}.json", cv.name)),
+            record: json!({
+                "name": cv.name,
+                "version": cv.version.to_string(),
+                "new_version": cv.remote_version.to_string(),
+                "source": source,
+                "features": cv.features,
+                "binaries": cv.binaries,
+                "backup": backup,
+            }),
+        };
+        journal.set_phase("uninstalling")?;
+        Ok(journal)
+    }
+
+    /// Records the step the upgrade is about to take
+    pub fn set_phase(&mut self, phase: &str) -> Result<()> {
+        self.record["phase"] = json!(phase);
+        write_atomic(&self.path, &serde_json::to_string_pretty(&self.record)?)
+    }
+
+    pub fn finish(self) {
+        if let Err(e) = remove_file(&self.path) {
+            println!("Could not remove journal {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// An upgrade that did not finish
+#[derive(Debug)]
+pub struct Interrupted {
+    pub crate_version: CrateVersion,
+    pub new_version: String,
+    pub phase: String,
+    pub backup: PathBuf,
+    /// The version to install when finishing the upgrade
+    target: Option<CrateVersion>,
+    path: PathBuf,
+}
+
+impl Interrupted {
+    fn read(path: PathBuf) -> Result<Interrupted> {
+        let record: Value = serde_json::from_str(&read_to_string(&path)?)?;
+        let invalid = || UpgradeError::Parse(format!("journal {}", path.display()));
+        let name = record["name"].as_str().ok_or_else(invalid)?;
+        let version = record["version"].as_str().and_then(|v| Version::parse(v).ok()).ok_or_else(invalid)?;
+
+        let mut cv = CrateVersion::new(name, version);
+        let source = &record["source"];
+        match (source["kind"].as_str(), source["url"].as_str(), source["commit"].as_str(), source["path"].as_str()) {
+            (Some("registry"), ..) => {},
+            (Some("git"), Some(url), Some(commit), _) => cv.set_repo(url, commit),
+            (Some("local"), _, _, Some(path)) => cv.set_path(path),
+            _ => return Err(invalid()),
+        }
+        let strings = |key: &str| -> Vec<String> {
+            record[key].as_array()
+                .map(|a| a.iter().filter_map(Value::as_str).map(String::from).collect())
+                .unwrap_or_default()
+        };
+        cv.set_features(&strings("features"));
+        cv.set_binaries(&strings("binaries").into_iter().map(PathBuf::from).collect::<Vec<_>>());
+
+        let new_version = record["new_version"].as_str().unwrap_or("?").to_owned();
+        Ok(Interrupted {
+            target: target(&cv, &new_version, source),
+            crate_version: cv,
+            new_version,
+            phase: record["phase"].as_str().unwrap_or("?").to_owned(),
+            backup: record["backup"].as_str().map(PathBuf::from).ok_or_else(invalid)?,
+            path,
+        })
+    }
+
+    /// Removes the journal and the backup
+    fn close(self) {
+        let _ = remove_dir_all(&self.backup);
+        let _ = remove_file(&self.path);
+    }
+}
+
+/// The version an upgrade was about to install. Git sources are pinned to the new commit,
+/// local sources can only be installed as they are. None if the journal lacks the information.
+fn target(cv: &CrateVersion, new_version: &str, source: &Value) -> Option<CrateVersion> {
+    let mut target = cv.clone();
+    target.version = Version::parse(new_version).ok()?;
+    if let PackageSource::Git { ref url, .. } = cv.source {
+        target.set_repo(url.as_str(), source["new_commit"].as_str()?);
+    }
+    Some(target)
+}
+
+/// Upgrades of earlier runs that did not finish
+pub fn interrupted(cfg: &Config) -> Vec<Interrupted> {
+    let Ok(entries) = read_dir(cfg.cpath.join(JOURNAL_DIR)) else { return Vec::new() };
+    let mut found = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        match Interrupted::read(path) {
+            Ok(i) => found.push(i),
+            Err(e) if !cfg.quiet() => println!("Ignoring damaged journal: {}", e),
+            Err(e) => debug!("Ignoring damaged journal: {}", e),
+        }
+    }
+    found
+}
+
+/// Deals with upgrades that were interrupted by a crash.
+/// Asks what to do if no recovery action is configured and stdin is a terminal.
+/// Returns false if unfinished upgrades are left.
+pub fn recover(cfg: &Config) -> bool {
+    let mut clean = true;
+    for interrupted in interrupted(cfg) {
+        let cv = &interrupted.crate_version;
+        println!("The upgrade of {} from {} to {} was interrupted while {}.",
+                 cv.name, cv.version, interrupted.new_version, interrupted.phase);
+        let action = match cfg.recover {
+            Some(action) => Some(action),
+            None if stdin().is_terminal() => ask(),
+            None => None,
+        };
+        match action {
+            Some(Recovery::Restore) => {
+                println!("Restoring {} {}", cv.name, cv.version);
+                cv.reverse_backup(&interrupted.backup, cfg);
+                interrupted.close();
+            }
+            Some(Recovery::Finish) => {
+                let Some(ref target) = interrupted.target else {
+                    println!("The journal of {} does not record the new version. Resolve with --recover restore|discard",
+                             cv.name);
+                    clean = false;
+                    continue;
+                };
+                println!("Finishing the upgrade of {} to {}", cv.name, target.version);
+                // installs exactly the recorded version, the log directory may not exist yet
+                if !target.reinstall(cfg) {
+                    println!("Upgrade not successful. Use backup");
+                    cv.reverse_backup(&interrupted.backup, cfg);
+                }
+                interrupted.close();
+            }
+            Some(Recovery::Discard) => interrupted.close(),
+            None => {
+                println!("Backup in {}. Resolve with --recover restore|finish|discard",
+                         interrupted.backup.display());
+                clean = false;
+            }
+        }
+    }
+    clean
+}
+
+fn ask() -> Option<Recovery> {
+    loop {
+        println!("[r]estore the previous version, [f]inish the upgrade, [d]iscard the backup or [s]kip?");
+        let mut answer = String::new();
+        if stdin().read_line(&mut answer).ok()? == 0 {
+            return None;
+        }
+        match answer.trim() {
+            "r" => return Some(Recovery::Restore),
+            "f" => return Some(Recovery::Finish),
+            "d" => return Some(Recovery::Discard),
+            "s" => return None,
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    fn crate_version(cfg: &Config) -> CrateVersion {
+        let mut cv = CrateVersion::new("foo", Version::new(1, 0, 0));
+        cv.remote_version = Version::new(1, 1, 0);
+        cv.set_features(&[String::from("serde")]);
+        cv.set_binaries(&[cfg.cpath.join("bin").join("foo")]);
+        cv
+    }
+
+    /// Writes the journal of `cv` and reads it back
+    fn round_trip(cfg: &Config, cv: &CrateVersion) -> Interrupted {
+        let mut journal = Journal::begin(cfg, cv, &cfg.cpath.join("backup")).unwrap();
+        journal.set_phase("installing").unwrap();
+        let mut found = interrupted(cfg);
+        assert_eq!(found.len(), 1);
+        found.remove(0)
+    }
+
+    fn write_journal(cfg: &Config, name: &str, content: &str) {
+        let dir = cfg.cpath.join(JOURNAL_DIR);
+        create_dir_all(&dir).unwrap();
+        write(dir.join(name), content).unwrap();
+    }
+
+    #[test]
+    fn registry_round_trip() {
+        let tmp = TempDir::new("journal").unwrap();
+        let cfg = Config::for_test(tmp.path());
+        let cv = crate_version(&cfg);
+        let i = round_trip(&cfg, &cv);
+        assert_eq!(i.crate_version.name, "foo");
+        assert_eq!(i.crate_version.version, Version::new(1, 0, 0));
+        assert_eq!(i.crate_version.source, PackageSource::CratesIo);
+        assert_eq!(i.crate_version.features, cv.features);
+        assert_eq!(i.crate_version.binaries, cv.binaries);
+        assert_eq!(i.new_version, "1.1.0");
+        assert_eq!(i.phase, "installing");
+        assert_eq!(i.backup, tmp.path().join("backup"));
+        let target = i.target.as_ref().unwrap();
+        assert_eq!(target.version, Version::new(1, 1, 0));
+        assert_eq!(target.source, PackageSource::CratesIo);
+
+        i.close();
+        assert!(interrupted(&cfg).is_empty());
+    }
+
+    #[test]
+    fn git_target_is_pinned_to_the_new_commit() {
+        let tmp = TempDir::new("journal").unwrap();
+        let cfg = Config::for_test(tmp.path());
+        let mut cv = crate_version(&cfg);
+        cv.source = PackageSource::Git {
+            url: String::from("https://example.com/foo?branch=dev"),
+            commit: String::from("0ld"),
+            remote_commit: String::from("n3w"),
+        };
+        let i = round_trip(&cfg, &cv);
+        assert!(matches!(i.crate_version.source, PackageSource::Git { ref commit, .. } if commit == "0ld"));
+        let target = i.target.unwrap();
+        assert_eq!(target.version, Version::new(1, 1, 0));
+        assert!(matches!(target.source, PackageSource::Git { ref url, ref commit, .. }
+                         if url == "https://example.com/foo?branch=dev" && commit == "n3w"));
+    }
+
+    #[test]
+    fn local_round_trip() {
+        let tmp = TempDir::new("journal").unwrap();
+        let cfg = Config::for_test(tmp.path());
+        let mut cv = crate_version(&cfg);
+        cv.set_path("/home/u/foo");
+        let i = round_trip(&cfg, &cv);
+        assert_eq!(i.crate_version.source, PackageSource::Local { path: PathBuf::from("/home/u/foo") });
+        assert_eq!(i.target.unwrap().source, PackageSource::Local { path: PathBuf::from("/home/u/foo") });
+    }
+
+    #[test]
+    fn journal_without_the_new_version() {
+        let tmp = TempDir::new("journal").unwrap();
+        let cfg = Config::for_test(tmp.path());
+        write_journal(&cfg, "foo.json", r#"{"name": "foo", "version": "1.0.0", "source": {"kind": "registry"},
+                                             "backup": "/tmp/backup", "phase": "installing"}"#);
+        write_journal(&cfg, "bar.json", r#"{"name": "bar", "version": "1.0.0", "new_version": "1.1.0",
+                                             "source": {"kind": "git", "url": "https://example.com/bar", "commit": "0ld"},
+                                             "backup": "/tmp/backup"}"#);
+        let found = interrupted(&cfg);
+        assert_eq!(found.len(), 2);
+        for i in found {
+            assert!(i.target.is_none(), "{} has a target", i.crate_version.name);
+        }
+    }
+
+    #[test]
+    fn damaged_journals_are_ignored() {
+        let tmp = TempDir::new("journal").unwrap();
+        let cfg = Config::for_test(tmp.path());
+        assert!(interrupted(&cfg).is_empty());
+        write_journal(&cfg, "empty.json", "");
+        write_journal(&cfg, "broken.json", "{\"name\": \"foo\"");
+        write_journal(&cfg, "noname.json", r#"{"version": "1.0.0", "source": {"kind": "registry"}, "backup": "/b"}"#);
+        write_journal(&cfg, "badversion.json", r#"{"name": "foo", "version": "1.x", "source": {"kind": "registry"}, "backup": "/b"}"#);
+        write_journal(&cfg, "badsource.json", r#"{"name": "foo", "version": "1.0.0", "source": {"kind": "git"}, "backup": "/b"}"#);
+        write_journal(&cfg, "nobackup.json", r#"{"name": "foo", "version": "1.0.0", "source": {"kind": "registry"}}"#);
+        write_journal(&cfg, "notes.txt", "not a journal");
+        assert!(interrupted(&cfg).is_empty());
+        assert!(Interrupted::read(cfg.cpath.join(JOURNAL_DIR).join("broken.json")).is_err());
+    }
+}
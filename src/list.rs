@@ -0,0 +1,100 @@
+use serde_json::{json, Value};
+
+use std::path::Path;
+
+use crate::config::{Config, OutputFormat};
+use crate::crateversion::{CrateVersion, PackageSource};
+use crate::util::read_installed_packages;
+
+/// Prints the installed crates and returns the exit code
+pub fn run(cfg: &Config, format: OutputFormat, missing_only: bool) -> i32 {
+    let mut installed = match read_installed_packages(cfg) {
+        Ok(installed) => installed,
+        Err(e) => {
+            eprintln!("Could not read installed packages: {}", e);
+            return crate::EXIT_FAILED;
+        }
+    };
+    // not part of the listing, which may be parsed
+    for msg in cfg.select(&mut installed) {
+        eprintln!("{}", msg);
+    }
+    if missing_only {
+        installed.retain(|cv| cv.binaries.iter().any(|b| !b.exists()));
+    }
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    match format {
+        OutputFormat::Human => installed.iter().for_each(print_human),
+        OutputFormat::Json => {
+            let list: Vec<Value> = installed.iter().map(to_json).collect();
+            println!("{}", serde_json::to_string_pretty(&list).unwrap_or_default());
+        }
+    }
+    0
+}
+
+fn print_human(cv: &CrateVersion) {
+    println!("{} {}", cv.name, cv.version);
+    match cv.source {
+        PackageSource::CratesIo => println!("    source:   crates.io"),
+        PackageSource::Git { ref url, ref commit, .. } => {
+            let url = url.split_once('?').map_or(url.as_str(), |(u, _)| u);
+            println!("    source:   git {}", url);
+            if let Some(git_ref) = cv.git_ref() {
+                println!("    ref:      {}", git_ref);
+            }
+            println!("    commit:   {}", commit);
+        }
+        PackageSource::Local { ref path } => println!("    source:   local {}", path.display()),
+    }
+    let mut features = if cv.features_known { cv.features.join(", ") } else { String::from("unknown") };
+    if cv.all_features {
+        features = String::from("all");
+    }
+    if cv.no_default_features {
+        features.push_str(if features.is_empty() { "no default" } else { " (no default)" });
+    }
+    if !features.is_empty() {
+        println!("    features: {}", features);
+    }
+    println!("    profile:  {}", cv.profile.as_deref().unwrap_or("unknown"));
+    println!("    target:   {}", cv.target.as_deref().unwrap_or("unknown"));
+    println!("    rustc:    {}", cv.rustc.as_deref().unwrap_or("unknown"));
+    for binary in &cv.binaries {
+        let present = if binary.exists() { "" } else { " (missing)" };
+        println!("    binary:   {}{}", bin_name(binary), present);
+    }
+}
+
+fn to_json(cv: &CrateVersion) -> Value {
+    let source = match cv.source {
+        PackageSource::CratesIo => json!({ "kind": "registry" }),
+        PackageSource::Git { ref url, ref commit, .. } => json!({
+            "kind": "git",
+            "url": url.split_once('?').map_or(url.as_str(), |(u, _)| u),
+            "ref": cv.git_ref(),
+            "commit": commit,
+        }),
+        PackageSource::Local { ref path } => json!({ "kind": "local", "path": path }),
+    };
+    let binaries: Vec<Value> = cv.binaries.iter()
+        .map(|b| json!({ "name": bin_name(b), "path": b, "present": b.exists() }))
+        .collect();
+    json!({
+        "name": cv.name,
+        "version": cv.version.to_string(),
+        "source": source,
+        "features": cv.features_known.then_some(&cv.features),
+        "all_features": cv.features_known.then_some(cv.all_features),
+        "no_default_features": cv.features_known.then_some(cv.no_default_features),
+        "profile": cv.profile,
+        "target": cv.target,
+        "rustc": cv.rustc,
+        "binaries": binaries,
+    })
+}
+
+fn bin_name(path: &Path) -> String {
+    path.file_name().map(|f| f.to_string_lossy().into_owned()).unwrap_or_default()
+}
@@ -11,22 +11,59 @@ extern crate tempdir;
 
 #[macro_use] extern crate log;
 extern crate env_logger;
+extern crate terminal_size;
 
 mod util;
 mod crateversion;
 mod config;
 mod error;
+mod index;
+mod cache;
+mod hooks;
+mod state;
+mod manifest;
+mod journal;
+mod registry;
+mod plan;
+mod list;
+mod outdated;
+mod progress;
+mod bins;
+mod metadata;
+mod doctor;
 
 use clap::Command;
 
 use std::path::PathBuf;
+use std::time::Duration;
 
 use crate::config::*;
 use crate::util::*;
 use crate::error::UpgradeError;
+use crate::crateversion::{Lookup, Outcome, SourceKind, Status};
+use crate::cache::Cache;
+use crate::state::State;
+use crate::progress::Progress;
+
+/// Exit code if at least one upgrade failed
+pub const EXIT_FAILED: i32 = 1;
+/// Exit code if at least one crate could not be checked
+pub const EXIT_UNKNOWN: i32 = 2;
+/// Exit code of the check mode if updates are available
+pub const EXIT_OUTDATED: i32 = 10;
+
+/// Prints a status line unless the run is quiet
+macro_rules! report {
+    ($cfg:expr, $($arg:tt)*) => {
+        if !$cfg.quiet() {
+            println!($($arg)*);
+        }
+    };
+}
 
 fn main() {
     env_logger::init();
+    install_signal_handler();
     
 
     let m = Command::new("cargo-install-upgrade")
@@ -38,25 +75,85 @@ fn main() {
         .subcommand_required(true)
         .subcommand(Command::new("install-upgrade")
             .about("Updates crates installed with cargo install")
-            .args(&[
-               arg!(-p --packages [PKG]...   "Crates to upgrade (defaults to all)"),
+            .subcommand(Command::new("check")
+                .about("Only check for updates. Exits with 10 if updates are available"))
+            .subcommand(Command::new("list")
+                .about("Lists the installed crates")
+                .args([
+                    arg!(--format [FORMAT]    "Output format")
+                        .value_parser(["human", "json"]).default_value("human"),
+                    arg!(--missing            "Only list crates with missing binaries"),
+                ]))
+            .subcommand(Command::new("bins")
+                .about("Compares the recorded binaries with the files in the bin directory")
+                .arg(arg!(--repair            "Reinstall the recorded version of crates with missing binaries")))
+            .subcommand(Command::new("doctor")
+                .about("Validates the install metadata of cargo and suggests fixes")
+                .arg(arg!(--fix               "Apply the fixes that only restore what cargo recorded elsewhere")))
+            .subcommand(Command::new("outdated")
+                .about("Shows the crates with updates as a table")
+                .arg(arg!(--color [WHEN]      "When to color the table")
+                    .value_parser(["auto", "always", "never"]).default_value("auto")))
+            .args([
+               arg!(-p --packages [PKG]...   "Crates to upgrade (defaults to all), may contain the wildcards * and ?"),
                arg!(-f --force               "Force a reinstall of git/local packages"),
                arg!(-v --verbose             "Verbose output"),
                arg!(-c --cargo [DIR]         "Path to Cargo home directory"),
                arg!(-d --dryrun              "Do not perform actual upgrades'"),
-               arg!(-e --exclude [PKG]...    "crates to exclude").conflicts_with("packages")
-            ])
+               arg!(-e --exclude [PKG]...    "crates to exclude").conflicts_with("packages"),
+               arg!(--source [KIND]...       "Only select crates from these kinds of sources")
+                   .value_parser(["registry", "git", "local"]),
+               arg!(--offline                "Check versions against the local registry index cache"),
+               arg!(--refresh                "Ignore cached remote versions"),
+               arg!(--"cache-ttl" [SECS]     "Time to keep remote versions cached, 0 disables the cache")
+                   .value_parser(value_parser!(u64)).default_value("3600"),
+               arg!(--check                  "Same as the check subcommand"),
+               arg!(--pull                   "Fast-forward local git checkouts to their upstream before checking"),
+               arg!(--verify                 "Check the installed binaries and roll back if they fail"),
+               arg!(--"allow-conflicts"      "Upgrade even if binaries of other crates get replaced"),
+               arg!(--recover [ACTION]       "What to do with upgrades interrupted by a crash")
+                   .value_parser(["restore", "finish", "discard"]),
+               arg!(--"log-lines" [N]        "Lines of the build log to show if an upgrade fails")
+                   .value_parser(value_parser!(usize)).default_value("20"),
+               arg!(--retries [N]            "Retries of lookups and downloads that failed with a network error")
+                   .value_parser(value_parser!(u32)).default_value("3"),
+               arg!(--"lookup-timeout" [SECS]  "Time limit for git and registry lookups of a crate, 0 disables it")
+                   .value_parser(value_parser!(u64)).default_value("120"),
+               arg!(--"build-timeout" [SECS]   "Time limit for building a crate, 0 disables it")
+                   .value_parser(value_parser!(u64)).default_value("0"),
+               arg!(--"hook-timeout" [SECS]    "Time limit for hooks and test commands, 0 disables it")
+                   .value_parser(value_parser!(u64)).default_value("300"),
+               arg!(--config [FILE]          "Path to the configuration file (defaults to <cargo home>/install-upgrade.toml)"),
+               #[cfg(feature = "regex")]
+               arg!(--regex                  "Match -p and -e as regular expressions instead of globs"),
+            ].map(|a| a.global(true)))
         ).get_matches();
 
 
     if let Some(m) = m.subcommand_matches("install-upgrade") {
+        let (m, sub) = match m.subcommand() {
+            Some((name, sm)) => (sm, Some(name)),
+            None => (m, None),
+        };
+        let check = matches!(sub, Some("check" | "outdated")) || m.get_flag("check");
 
-        let mode = match (m.get_many::<String>("packages"), m.get_many::<String>("exclude")) {
-            (None, None) => PackageMode::All,
-            (Some(m), None) => PackageMode::Include(m.map(String::from).collect()),
-            (None, Some(m)) => PackageMode::Exclude(m.map(String::from).collect()),
-            (Some(_), Some(_)) => unreachable!(),
+        let mode = match (patterns(m, "packages"), patterns(m, "exclude")) {
+            (Ok(None), Ok(None)) => PackageMode::All,
+            (Ok(Some(p)), Ok(None)) => PackageMode::Include(p),
+            (Ok(None), Ok(Some(p))) => PackageMode::Exclude(p),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("Invalid package selection: {}", e);
+                std::process::exit(EXIT_FAILED);
+            }
+            (Ok(Some(_)), Ok(Some(_))) => unreachable!(),
         };
+        let sources = m.get_many::<String>("source").into_iter().flatten()
+            .map(|s| match s.as_str() {
+                "git" => SourceKind::Git,
+                "local" => SourceKind::Local,
+                _ => SourceKind::Registry,
+            })
+            .collect();
 
         let home = match m.get_one::<String>("cargo").map(|s| s.as_str()) {
             Some(val) => Some(PathBuf::from(val)),
@@ -64,53 +161,203 @@ fn main() {
         };
 
         if let Some(home) = home {
+            let cfgfile = match m.get_one::<String>("config") {
+                Some(val) => PathBuf::from(val),
+                None => home.join(CONFIG_FILE),
+            };
+            let settings = match Settings::load(&cfgfile) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    eprintln!("Could not read {}: {}", cfgfile.display(), e);
+                    std::process::exit(EXIT_FAILED);
+                }
+            };
             let cfg = Config {
-                upgrade: !m.get_one::<bool>("dryrun").map_or_else(|| false, |b| *b),
+                upgrade: matches!(sub, None | Some("bins")) && !check && !m.get_one::<bool>("dryrun").map_or_else(|| false, |b| *b),
+                check,
                 force: m.get_one::<bool>("force").map_or_else(|| false, |b| *b),
                 verbose: m.get_one::<bool>("verbose").map_or_else(|| false, |b| *b),
+                offline: m.get_one::<bool>("offline").map_or_else(|| false, |b| *b),
+                refresh: m.get_one::<bool>("refresh").map_or_else(|| false, |b| *b),
+                pull: m.get_one::<bool>("pull").map_or_else(|| false, |b| *b),
+                verify: m.get_one::<bool>("verify").map_or_else(|| false, |b| *b),
+                allow_conflicts: m.get_one::<bool>("allow-conflicts").map_or_else(|| false, |b| *b),
+                recover: match m.get_one::<String>("recover").map(|s| s.as_str()) {
+                    Some("restore") => Some(Recovery::Restore),
+                    Some("finish") => Some(Recovery::Finish),
+                    Some("discard") => Some(Recovery::Discard),
+                    _ => None,
+                },
+                cache_ttl: Duration::from_secs(*m.get_one::<u64>("cache-ttl").unwrap()),
+                log_lines: *m.get_one::<usize>("log-lines").unwrap(),
+                retries: *m.get_one::<u32>("retries").unwrap(),
+                lookup_timeout: timeout(m, "lookup-timeout"),
+                build_timeout: timeout(m, "build-timeout"),
+                hook_timeout: timeout(m, "hook-timeout"),
                 mode,
+                sources,
                 cpath: home,
+                settings,
             };
             debug!("{:?}", cfg);
-            execute(cfg);
+            std::process::exit(match sub {
+                Some("list") => {
+                    let format = match m.get_one::<String>("format").map(|s| s.as_str()) {
+                        Some("json") => OutputFormat::Json,
+                        _ => OutputFormat::Human,
+                    };
+                    list::run(&cfg, format, m.get_flag("missing"))
+                }
+                Some("bins") => bins::run(&cfg, m.get_flag("repair")),
+                Some("doctor") => doctor::run(&cfg, m.get_flag("fix")),
+                Some("outdated") => {
+                    let color = match m.get_one::<String>("color").map(|s| s.as_str()) {
+                        Some("always") => ColorChoice::Always,
+                        Some("never") => ColorChoice::Never,
+                        _ => ColorChoice::Auto,
+                    };
+                    outdated::run(&cfg, color)
+                }
+                _ => execute(cfg),
+            });
         } else {
             println!("Could not find cargo home directory. Please set it manually with -c.");
+            std::process::exit(EXIT_FAILED);
         }
     }
 }
 
-fn execute(cfg: Config) {
+/// Reads the crate patterns of `-p` or `-e`
+fn patterns(m: &clap::ArgMatches, id: &str) -> Result<Option<Vec<Pattern>>, UpgradeError> {
+    let Some(values) = m.get_many::<String>(id) else { return Ok(None) };
+    #[cfg(feature = "regex")]
+    if m.get_flag("regex") {
+        return values.map(|v| Pattern::regex(v)).collect::<Result<_, _>>().map(Some);
+    }
+    Ok(Some(values.map(|v| Pattern::Glob(v.clone())).collect()))
+}
+
+/// Reads a timeout in seconds, 0 means no timeout
+fn timeout(m: &clap::ArgMatches, name: &str) -> Option<Duration> {
+    m.get_one::<u64>(name).filter(|s| **s > 0).map(|s| Duration::from_secs(*s))
+}
+
+/// Runs the upgrade and returns the exit code
+fn execute(cfg: Config) -> i32 {
+    if cfg.upgrade {
+        if !journal::recover(&cfg) {
+            return EXIT_FAILED;
+        }
+    } else {
+        for i in journal::interrupted(&cfg) {
+            report!(cfg, "The upgrade of {} was interrupted. Run an upgrade to recover.", i.crate_version.name);
+        }
+    }
+
     info!("Searc for packages");
-    let Ok(mut installed) = read_installed_packages(&cfg) else { return };
+    let mut installed = match read_installed_packages(&cfg) {
+        Ok(installed) => installed,
+        Err(e) => {
+            eprintln!("Could not read installed packages: {}", e);
+            return EXIT_FAILED;
+        }
+    };
     info!("Found packages: {:?}", installed);
 
-    match cfg.mode {
-        PackageMode::Include(ref pack) => {
-            installed.retain(|x| pack.contains(&x.name));
-        },
-        PackageMode::Exclude(ref pack) => {
-            installed.retain(|x| !pack.contains(&x.name));
-        },
-        _ => {},
-    };
+    let owners = plan::bin_owners(&installed);
+    for (bin, crates) in owners.iter().filter(|(_, c)| c.len() > 1) {
+        report!(cfg, "{} is claimed by several crates: {}", bin, crates.join(", "));
+    }
 
-    for crate_version in &mut installed {
+    let not_selected = cfg.select(&mut installed);
+
+    let mut cache = Cache::load(&cfg);
+    let mut state = State::load(&cfg);
+    let mut unknown = Vec::new();
+    let mut outdated = Vec::new();
+    let mut failed = Vec::new();
+    let mut skipped = Vec::new();
+    let mut queue = Vec::new();
+    let mut progress = Progress::new(vec![None; installed.len()], !cfg.quiet());
+    for (i, crate_version) in installed.iter_mut().enumerate() {
+        progress.next();
+        progress.status(&format!("checking {}", crate_version.name));
         debug!("before: {}", crate_version);
-        crate_version.get_remote_version(&cfg);
+        crate_version.get_remote_version(&cfg, &mut cache);
+        crate_version.check_sources(&mut state, &cfg);
         debug!("after: {}", crate_version);
+        progress.clear();
 
-        match (crate_version.new_remote_version(), cfg.force, crate_version.is_cratesio()) {
-            (true,_,_) | (_,true,_) => crate_version.upgrade(&cfg),
-            (false,false,false) =>
+        let age = crate_version.age_note();
+        match (crate_version.status(), cfg.force, crate_version.is_cratesio()) {
+            (Status::Outdated,_,_) if cfg.check => {
+                report!(cfg, "Update available {}{}", crate_version, age);
+                outdated.push(crate_version.name.clone());
+            }
+            (Status::Outdated,_,_) | (_,true,_) if !cfg.check => queue.push(i),
+            (Status::Unknown,_,_) => {
+                if let Lookup::Failed(ref reason) = crate_version.lookup {
+                    report!(cfg, "{} could not be checked: {}", crate_version.name, reason);
+                }
+                unknown.push(crate_version.name.clone());
+            }
+            (Status::UpToDate,false,false) if !cfg.check && crate_version.sources_changed.is_none() =>
                 println!("{} is a local/git package. Force an upgrade with -f", crate_version),
-            _ => println!("{} is up to date.", crate_version),
+            _ => report!(cfg, "{} is up to date.{}", crate_version, age),
         }
     }
 
-    if let PackageMode::Include(ref packages) = cfg.mode {
-        let installed: Vec<String> = installed.into_iter().map(|x| x.name).collect();
-        for n in packages.iter().filter(|x| !installed.contains(x)) {
-            println!("{} is not installed.", n);
+    let estimates = queue.iter().map(|i| state.duration(&installed[*i].name)).collect();
+    let mut progress = Progress::new(estimates, cfg.upgrade);
+    if let (true, Some(total)) = (cfg.upgrade, progress.remaining()) {
+        println!("Upgrading {} crate(s), estimated time {}", queue.len(), fmt_age(total));
+    }
+    for i in queue {
+        let crate_version = &mut installed[i];
+        progress.next();
+        let outcome = match plan::review(crate_version, &owners, &cfg, &mut cache) {
+            true => crate_version.upgrade(&cfg, &progress),
+            false => Outcome::Skipped,
+        };
+        match outcome {
+            Outcome::Failed => failed.push(crate_version.name.clone()),
+            Outcome::Skipped => skipped.push(crate_version.name.clone()),
+            Outcome::Upgraded if cfg.upgrade => {
+                if let Some(ref fp) = crate_version.fingerprint {
+                    state.set_fingerprint(&crate_version.name, fp);
+                }
+                state.set_duration(&crate_version.name, progress.elapsed());
+            }
+            Outcome::Upgraded => {},
         }
     }
+
+    if let Err(e) = cache.store() {
+        report!(cfg, "Could not update the version cache: {}", e);
+    }
+    if let Err(e) = state.store() {
+        report!(cfg, "Could not save the install state: {}", e);
+    }
+
+    for msg in not_selected {
+        report!(cfg, "{}", msg);
+    }
+
+    if !unknown.is_empty() {
+        report!(cfg, "Update state unknown for {} crate(s): {}", unknown.len(), unknown.join(", "));
+    }
+    if !skipped.is_empty() {
+        println!("Skipped {} crate(s): {}", skipped.len(), skipped.join(", "));
+    }
+    if !failed.is_empty() {
+        println!("Upgrade failed for {} crate(s): {}", failed.len(), failed.join(", "));
+        EXIT_FAILED
+    } else if !outdated.is_empty() {
+        report!(cfg, "Updates available for {} crate(s): {}", outdated.len(), outdated.join(", "));
+        EXIT_OUTDATED
+    } else if !unknown.is_empty() {
+        EXIT_UNKNOWN
+    } else {
+        0
+    }
 }
@@ -0,0 +1,314 @@
+use semver::Version;
+use toml::{Table, Value};
+
+use std::fs::{read_dir, read_to_string};
+use std::result;
+use std::path::{Path, PathBuf};
+
+use crate::crateversion::Result;
+use crate::error::UpgradeError;
+use crate::util::wildcard_match;
+
+/// Package information read from a Cargo.toml
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub name: String,
+    pub version: Version,
+    pub rust_version: Option<String>,
+    pub features: Vec<String>,
+    pub bins: Vec<String>,
+    /// Directory of the package
+    pub dir: PathBuf,
+}
+
+/// Finds a package by name, either in `path` itself or in the workspace `path` belongs to
+pub fn find_package(path: &Path, name: &str) -> Result<Manifest> {
+    let manifest = path.join("Cargo.toml");
+    let table = read_manifest(&manifest)?;
+    let root = if table.contains_key("workspace") {
+        Some((path.to_path_buf(), table.clone()))
+    } else {
+        find_workspace_root(path)
+    };
+
+    if package_name(&table) == Some(name) {
+        return parse_package(path, &table, root.as_ref().map(|r| &r.1));
+    }
+    if let Some((ref rootdir, ref roottable)) = root {
+        for member in workspace_members(rootdir, roottable) {
+            let Ok(table) = read_manifest(&member.join("Cargo.toml")) else { continue };
+            if package_name(&table) == Some(name) {
+                return parse_package(&member, &table, Some(roottable));
+            }
+        }
+    }
+    Err(UpgradeError::NoCrate(format!("{} not found in {}", name, manifest.display())))
+}
+
+fn read_manifest(path: &Path) -> Result<Table> {
+    let content = read_to_string(path)
+        .map_err(|e| UpgradeError::Manifest(path.to_path_buf(), e.to_string()))?;
+    content.parse()
+        .map_err(|e: toml::de::Error| UpgradeError::Manifest(path.to_path_buf(), e.message().to_owned()))
+}
+
+fn package_name(table: &Table) -> Option<&str> {
+    table.get("package")?.get("name")?.as_str()
+}
+
+/// Searches the parent directories for a manifest with a `[workspace]` section
+fn find_workspace_root(path: &Path) -> Option<(PathBuf, Table)> {
+    path.ancestors().skip(1).find_map(|dir| {
+        let table = read_manifest(&dir.join("Cargo.toml")).ok()?;
+        table.contains_key("workspace").then(|| (dir.to_path_buf(), table))
+    })
+}
+
+/// Directories of all workspace members. Supports wildcards in path components.
+fn workspace_members(rootdir: &Path, root: &Table) -> Vec<PathBuf> {
+    let strings = |key: &str| -> Vec<&str> {
+        root.get("workspace")
+            .and_then(|w| w.get(key))
+            .and_then(Value::as_array)
+            .map(|a| a.iter().filter_map(Value::as_str).collect())
+            .unwrap_or_default()
+    };
+    let exclude: Vec<PathBuf> = strings("exclude").iter().map(|e| rootdir.join(e)).collect();
+
+    let mut members = Vec::new();
+    for pattern in strings("members") {
+        let mut dirs = vec![rootdir.to_path_buf()];
+        for component in Path::new(pattern).components() {
+            let component = component.as_os_str().to_string_lossy();
+            if !component.contains(['*', '?']) {
+                dirs.iter_mut().for_each(|d| d.push(component.as_ref()));
+                continue;
+            }
+            dirs = dirs.iter()
+                .filter_map(|d| read_dir(d).ok())
+                .flat_map(|entries| entries.flatten())
+                .filter(|e| wildcard_match(&component, &e.file_name().to_string_lossy()))
+                .map(|e| e.path())
+                .collect();
+        }
+        members.extend(dirs.into_iter().filter(|d| d.is_dir() && !exclude.contains(d)));
+    }
+    members
+}
+
+fn parse_package(dir: &Path, table: &Table, root: Option<&Table>) -> Result<Manifest> {
+    let manifest = dir.join("Cargo.toml");
+    let invalid = |msg: &str| UpgradeError::Manifest(manifest.clone(), msg.to_owned());
+    let package = table.get("package").and_then(Value::as_table).ok_or_else(|| invalid("no [package] section"))?;
+    let name = package_name(table).ok_or_else(|| invalid("package has no name"))?;
+
+    let version = match inherited(package, "version", root).map_err(invalid)? {
+        Some(Value::String(v)) => Version::parse(v).map_err(|e| invalid(&format!("version {}: {}", v, e)))?,
+        Some(_) => return Err(invalid("version is not a string")),
+        // cargo defaults to 0.0.0 for unpublished packages without a version
+        None => Version::new(0, 0, 0),
+    };
+    let rust_version = match inherited(package, "rust-version", root).map_err(invalid)? {
+        Some(Value::String(v)) => Some(v.clone()),
+        Some(_) => return Err(invalid("rust-version is not a string")),
+        None => None,
+    };
+
+    let manifest = Manifest {
+        name: name.to_owned(),
+        version,
+        rust_version,
+        features: features(table),
+        bins: bins(dir, name, package, table),
+        dir: dir.to_path_buf(),
+    };
+    debug!("{} {} (rust {:?}) bins: {:?} features: {:?}",
+           manifest.name, manifest.version, manifest.rust_version, manifest.bins, manifest.features);
+    Ok(manifest)
+}
+
+/// Reads a package field, following `field.workspace = true` to `[workspace.package]`
+fn inherited<'a>(package: &'a Table, field: &str, root: Option<&'a Table>) -> result::Result<Option<&'a Value>, &'static str> {
+    let value = package.get(field);
+    let inherits = value
+        .and_then(|v| v.get("workspace"))
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+    if !inherits {
+        return Ok(value);
+    }
+    root.and_then(|r| r.get("workspace"))
+        .and_then(|w| w.get("package"))
+        .and_then(|p| p.get(field))
+        .map(Some)
+        .ok_or("inherited field missing in [workspace.package]")
+}
+
+/// Explicit features and the implicit features of optional dependencies
+fn features(table: &Table) -> Vec<String> {
+    let explicit = table.get("features").and_then(Value::as_table);
+    let mut features: Vec<String> = explicit.map(|f| f.keys().cloned().collect()).unwrap_or_default();
+
+    let uses_dep = |dep: &str| {
+        let needle = format!("dep:{}", dep);
+        explicit.is_some_and(|f| f.values()
+            .filter_map(Value::as_array)
+            .flatten()
+            .any(|v| v.as_str() == Some(&needle)))
+    };
+    let targets = table.get("target").and_then(Value::as_table);
+    let dep_tables = std::iter::once(table)
+        .chain(targets.into_iter().flat_map(|t| t.values().filter_map(Value::as_table)));
+    for deps in dep_tables.filter_map(|t| t.get("dependencies").and_then(Value::as_table)) {
+        for (dep, spec) in deps {
+            let optional = spec.get("optional").and_then(Value::as_bool).unwrap_or(false);
+            if optional && !uses_dep(dep) && !features.contains(dep) {
+                features.push(dep.clone());
+            }
+        }
+    }
+    features.sort();
+    features
+}
+
+/// Names of the binary targets, explicit and auto discovered.
+/// Discovered targets are skipped if an explicit target has the same name or path.
+fn bins(dir: &Path, name: &str, package: &Table, table: &Table) -> Vec<String> {
+    let explicit: Vec<&Value> = table.get("bin")
+        .and_then(Value::as_array)
+        .map(|b| b.iter().collect())
+        .unwrap_or_default();
+    let mut bins: Vec<String> = explicit.iter()
+        .filter_map(|b| b.get("name")?.as_str().map(String::from))
+        .collect();
+    let paths: Vec<PathBuf> = explicit.iter()
+        .filter_map(|b| b.get("path")?.as_str().map(|p| dir.join(p)))
+        .collect();
+
+    if package.get("autobins").and_then(Value::as_bool).unwrap_or(true) {
+        let mut auto = Vec::new();
+        auto.push((name.to_owned(), dir.join("src").join("main.rs")));
+        if let Ok(entries) = read_dir(dir.join("src").join("bin")) {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let stem = entry.file_name().to_string_lossy().trim_end_matches(".rs").to_owned();
+                if path.extension().is_some_and(|e| e == "rs") {
+                    auto.push((stem, path));
+                } else {
+                    auto.push((stem, path.join("main.rs")));
+                }
+            }
+        }
+        bins.extend(auto.into_iter()
+            .filter(|(n, p)| p.is_file() && !bins.contains(n) && !paths.contains(p))
+            .map(|(n, _)| n)
+            .collect::<Vec<_>>());
+    }
+    bins.sort();
+    bins.dedup();
+    bins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use tempdir::TempDir;
+
+    fn file(dir: &Path, path: &str, content: &str) {
+        let path = dir.join(path);
+        create_dir_all(path.parent().unwrap()).unwrap();
+        write(path, content).unwrap();
+    }
+
+    fn workspace() -> TempDir {
+        let tmp = TempDir::new("manifest").unwrap();
+        let root = tmp.path();
+        file(root, "Cargo.toml", r#"
+[workspace]
+members = ["crates/*"]
+exclude = ["crates/skipped"]
+
+[workspace.package]
+version = "1.2.3"
+rust-version = "1.80"
+"#);
+        file(root, "crates/app/Cargo.toml", r#"
+[package]
+name = "app"
+version.workspace = true
+rust-version.workspace = true
+
+[dependencies]
+serde = { version = "1", optional = true }
+"#);
+        file(root, "crates/app/src/main.rs", "fn main() {}");
+        file(root, "crates/app/src/bin/tool.rs", "fn main() {}");
+        file(root, "crates/app/src/bin/multi/main.rs", "fn main() {}");
+        file(root, "crates/app/src/bin/notes.txt", "");
+        file(root, "crates/skipped/Cargo.toml", "[package]\nname = \"skipped\"\nversion = \"0.1.0\"\n");
+        tmp
+    }
+
+    #[test]
+    fn workspace_inheritance() {
+        let tmp = workspace();
+        let app = find_package(tmp.path(), "app").unwrap();
+        assert_eq!(app.version, Version::new(1, 2, 3));
+        assert_eq!(app.rust_version.as_deref(), Some("1.80"));
+        assert_eq!(app.features, ["serde"]);
+        assert_eq!(app.dir, tmp.path().join("crates/app"));
+        // found from the member directory as well
+        let app = find_package(&tmp.path().join("crates/app"), "app").unwrap();
+        assert_eq!(app.version, Version::new(1, 2, 3));
+        assert!(find_package(tmp.path(), "skipped").is_err());
+        assert!(find_package(tmp.path(), "nothing").is_err());
+    }
+
+    #[test]
+    fn missing_inherited_field() {
+        let tmp = workspace();
+        file(tmp.path(), "Cargo.toml", "[workspace]\nmembers = [\"crates/app\"]\n");
+        assert!(find_package(tmp.path(), "app").is_err());
+    }
+
+    #[test]
+    fn bin_autodiscovery() {
+        let tmp = workspace();
+        let app = find_package(tmp.path(), "app").unwrap();
+        assert_eq!(app.bins, ["app", "multi", "tool"]);
+    }
+
+    #[test]
+    fn explicit_bins() {
+        let tmp = workspace();
+        file(tmp.path(), "crates/app/Cargo.toml", r#"
+[package]
+name = "app"
+version = "0.1.0"
+
+[[bin]]
+name = "renamed"
+path = "src/main.rs"
+"#);
+        let app = find_package(tmp.path(), "app").unwrap();
+        assert_eq!(app.bins, ["multi", "renamed", "tool"]);
+
+        file(tmp.path(), "crates/app/Cargo.toml", "[package]\nname = \"app\"\nautobins = false\n");
+        let app = find_package(tmp.path(), "app").unwrap();
+        assert_eq!(app.version, Version::new(0, 0, 0));
+        assert!(app.bins.is_empty());
+    }
+
+    #[test]
+    fn malformed_manifests() {
+        let tmp = TempDir::new("manifest").unwrap();
+        assert!(find_package(tmp.path(), "app").is_err());
+        file(tmp.path(), "Cargo.toml", "[package\nname = \"app\"");
+        assert!(find_package(tmp.path(), "app").is_err());
+        file(tmp.path(), "Cargo.toml", "[package]\nname = \"app\"\nversion = \"one\"\n");
+        assert!(find_package(tmp.path(), "app").is_err());
+        file(tmp.path(), "Cargo.toml", "");
+        assert!(find_package(tmp.path(), "app").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use semver::Version;
+use serde_json::{Map, Value};
+use toml::Table;
+
+use std::fmt;
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+
+use crate::crateversion::{CrateVersion, Result};
+use crate::error::UpgradeError;
+
+/// Metadata files cargo keeps in the install root
+pub const CRATES_V1: &str = ".crates.toml";
+pub const CRATES_V2: &str = ".crates2.json";
+
+/// Identifies an installed package, e.g. `ripgrep 14.1.0 (registry+https://github.com/rust-lang/crates.io-index)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageId {
+    pub name: String,
+    pub version: String,
+    pub source: Source,
+}
+
+/// Source part of a package id
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Registry(String),
+    Git { url: String, commit: String },
+    Path(PathBuf),
+}
+
+impl PackageId {
+    pub fn parse(id: &str) -> Result<PackageId> {
+        let invalid = |why: &str| UpgradeError::Parse(format!("package id `{}`: {}", id, why));
+        let mut parts = id.splitn(3, ' ');
+        let (Some(name), Some(version), Some(source)) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid("expected `name version (source)`"));
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid("invalid crate name"));
+        }
+        let source = source.strip_prefix('(').and_then(|s| s.strip_suffix(')'))
+            .ok_or_else(|| invalid("source is not in parentheses"))?;
+        let source = match source.split_once('+') {
+            Some(("registry" | "sparse", url)) => Source::Registry(url.to_owned()),
+            Some(("git", url)) => match url.split_once('#') {
+                Some((url, commit)) if !commit.is_empty() => Source::Git { url: url.to_owned(), commit: commit.to_owned() },
+                _ => return Err(invalid("git source without commit")),
+            },
+            Some(("path", url)) => {
+                let prefix = if cfg!(target_os = "windows") { "file:///" } else { "file://" };
+                let path = url.strip_prefix(prefix).ok_or_else(|| invalid("path source is no file url"))?;
+                Source::Path(PathBuf::from(path))
+            }
+            _ => return Err(invalid("unknown source kind")),
+        };
+        Ok(PackageId { name: name.to_owned(), version: version.to_owned(), source })
+    }
+
+    /// A `CrateVersion` without the details of the installation. Fails for invalid versions.
+    pub fn crate_version(&self) -> Result<CrateVersion> {
+        let version = Version::parse(&self.version)
+            .map_err(|e| UpgradeError::Parse(format!("version {} of {}: {}", self.version, self.name, e)))?;
+        let mut cv = CrateVersion::new(self.name.as_str(), version);
+        match self.source {
+            Source::Registry(_) => {},
+            Source::Git { ref url, ref commit } => cv.set_repo(url.as_str(), commit.as_str()),
+            Source::Path(ref path) => cv.set_path(path.clone()),
+        }
+        Ok(cv)
+    }
+}
+
+impl fmt::Display for PackageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+/// Installs recorded in `.crates2.json`, by package id
+pub fn read_v2(cpath: &Path) -> Result<Map<String, Value>> {
+    let v: Value = serde_json::from_str(&read_to_string(cpath.join(CRATES_V2))?)?;
+    match v.get("installs") {
+        Some(Value::Object(installs)) => Ok(installs.clone()),
+        _ => Err(UpgradeError::Parse(format!("{}: no installs table", CRATES_V2))),
+    }
+}
+
+/// Binaries recorded in `.crates.toml`, by package id
+pub fn read_v1(cpath: &Path) -> Result<Table> {
+    let table: Table = read_to_string(cpath.join(CRATES_V1))?.parse()?;
+    match table.get("v1") {
+        Some(toml::Value::Table(v1)) => Ok(v1.clone()),
+        _ => Err(UpgradeError::Parse(format!("{}: no v1 table", CRATES_V1))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crateversion::PackageSource;
+
+    const INDEX: &str = "https://github.com/rust-lang/crates.io-index";
+
+    #[test]
+    fn parse_registry_ids() {
+        let id = PackageId::parse(&format!("ripgrep 14.1.0 (registry+{})", INDEX)).unwrap();
+        assert_eq!(id.name, "ripgrep");
+        assert_eq!(id.version, "14.1.0");
+        assert_eq!(id.source, Source::Registry(INDEX.to_owned()));
+        let id = PackageId::parse("cargo-edit 0.13.0 (sparse+https://index.crates.io/)").unwrap();
+        assert_eq!(id.source, Source::Registry("https://index.crates.io/".to_owned()));
+        assert_eq!(id.to_string(), "cargo-edit 0.13.0");
+    }
+
+    #[test]
+    fn parse_git_ids() {
+        let id = PackageId::parse("foo 0.1.0 (git+https://github.com/u/foo?branch=dev#0123abc)").unwrap();
+        assert_eq!(id.source, Source::Git {
+            url: "https://github.com/u/foo?branch=dev".to_owned(),
+            commit: "0123abc".to_owned(),
+        });
+        let cv = id.crate_version().unwrap();
+        assert!(matches!(cv.source, PackageSource::Git { ref commit, .. } if commit == "0123abc"));
+        assert!(PackageId::parse("foo 0.1.0 (git+https://github.com/u/foo)").is_err());
+        assert!(PackageId::parse("foo 0.1.0 (git+https://github.com/u/foo#)").is_err());
+    }
+
+    #[test]
+    fn parse_path_ids() {
+        let id = PackageId::parse("lc 0.7.0 (path+file:///home/u/lc)").unwrap();
+        assert_eq!(id.source, Source::Path(PathBuf::from("/home/u/lc")));
+        assert!(matches!(id.crate_version().unwrap().source, PackageSource::Local { .. }));
+        assert!(PackageId::parse("lc 0.7.0 (path+/home/u/lc)").is_err());
+    }
+
+    #[test]
+    fn parse_malformed_ids() {
+        assert!(PackageId::parse("").is_err());
+        assert!(PackageId::parse("ripgrep").is_err());
+        assert!(PackageId::parse("ripgrep 14.1.0").is_err());
+        assert!(PackageId::parse(&format!("ripgrep 14.1.0 registry+{}", INDEX)).is_err());
+        assert!(PackageId::parse(&format!("rip/grep 14.1.0 (registry+{})", INDEX)).is_err());
+        assert!(PackageId::parse(&format!(" 14.1.0 (registry+{})", INDEX)).is_err());
+        assert!(PackageId::parse("ripgrep 14.1.0 (svn+https://example.com)").is_err());
+        // the version is only checked when it is used
+        let id = PackageId::parse(&format!("ripgrep 14.x (registry+{})", INDEX)).unwrap();
+        assert!(id.crate_version().is_err());
+    }
+}
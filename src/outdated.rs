@@ -0,0 +1,208 @@
+use semver::{Version, VersionReq};
+
+use std::env;
+use std::io::{stdout, IsTerminal};
+
+use crate::cache::Cache;
+use crate::config::{ColorChoice, Config};
+use crate::crateversion::{CrateVersion, Lookup, PackageSource, Status};
+use crate::registry;
+use crate::state::State;
+use crate::util::{fmt_age, read_installed_packages, retry};
+use crate::{EXIT_FAILED, EXIT_OUTDATED, EXIT_UNKNOWN};
+
+const HEADER: [&str; 6] = ["Crate", "Installed", "Latest compatible", "Latest", "Source", "Notes"];
+/// Columns that get truncated if the table is wider than the terminal
+const SHRINKABLE: [usize; 2] = [4, 5];
+const MIN_WIDTH: usize = 12;
+
+const RED: &str = "\x1b[31m";
+const YELLOW: &str = "\x1b[33m";
+const GREEN: &str = "\x1b[32m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A table cell and its color
+type Cell = (String, Option<&'static str>);
+
+/// Prints the crates with updates as a table and returns the exit code of the check mode
+pub fn run(cfg: &Config, color: ColorChoice) -> i32 {
+    let mut installed = match read_installed_packages(cfg) {
+        Ok(installed) => installed,
+        Err(e) => {
+            eprintln!("Could not read installed packages: {}", e);
+            return EXIT_FAILED;
+        }
+    };
+    for msg in cfg.select(&mut installed) {
+        eprintln!("{}", msg);
+    }
+    installed.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut cache = Cache::load(cfg);
+    let mut state = State::load(cfg);
+    let mut rows = Vec::new();
+    let (mut outdated, mut unknown) = (false, false);
+    for cv in &mut installed {
+        cv.get_remote_version(cfg, &mut cache);
+        cv.check_sources(&mut state, cfg);
+        match cv.status() {
+            Status::Outdated => outdated = true,
+            Status::Unknown => unknown = true,
+            Status::UpToDate => continue,
+        }
+        rows.push(row(cv, cfg));
+    }
+    if let Err(e) = cache.store() {
+        println!("Could not update the version cache: {}", e);
+    }
+    if let Err(e) = state.store() {
+        println!("Could not save the install state: {}", e);
+    }
+
+    if rows.is_empty() {
+        println!("All crates are up to date.");
+    } else {
+        let color = match color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        };
+        print_table(&rows, color);
+    }
+
+    if outdated {
+        EXIT_OUTDATED
+    } else if unknown {
+        EXIT_UNKNOWN
+    } else {
+        0
+    }
+}
+
+fn row(cv: &CrateVersion, cfg: &Config) -> [Cell; 6] {
+    let mut notes = Vec::new();
+    let (compatible, latest) = match cv.lookup {
+        Lookup::Done => {
+            let major = !is_compatible(&cv.version, &cv.remote_version);
+            if major {
+                notes.push(String::from("major update"));
+            }
+            let color = if major { RED } else { GREEN };
+            let compatible = match latest_compatible(cv, cfg) {
+                Some(v) if v > cv.version => (v.to_string(), Some(GREEN)),
+                Some(v) => (v.to_string(), None),
+                None => (String::from("?"), None),
+            };
+            (compatible, (cv.remote_version.to_string(), Some(color)))
+        }
+        Lookup::Failed(ref reason) => {
+            notes.push(reason.clone());
+            ((String::from("?"), None), (String::from("?"), Some(YELLOW)))
+        }
+        Lookup::Pending => ((String::from("?"), None), (String::from("?"), None)),
+    };
+    if cv.sources_changed == Some(true) {
+        notes.push(String::from("sources changed"));
+    }
+    match (cv.index_age, cv.cache_age) {
+        (Some(age), _) => notes.push(format!("index cache is {} old", fmt_age(age))),
+        (_, Some(age)) => notes.push(format!("cached {} ago", fmt_age(age))),
+        _ => {},
+    }
+    let source = match cv.source {
+        PackageSource::CratesIo => String::from("crates.io"),
+        PackageSource::Git { ref url, .. } => format!("git {}", url),
+        PackageSource::Local { ref path } => format!("local {}", path.display()),
+    };
+    [
+        (cv.name.clone(), None),
+        (cv.version.to_string(), None),
+        compatible,
+        latest,
+        (source, None),
+        (notes.join(", "), None),
+    ]
+}
+
+/// True if `new` is semver compatible with `old`
+fn is_compatible(old: &Version, new: &Version) -> bool {
+    VersionReq::parse(&format!("^{}", old)).is_ok_and(|req| req.matches(new))
+}
+
+/// The newest version an upgrade without breaking changes would get
+fn latest_compatible(cv: &CrateVersion, cfg: &Config) -> Option<Version> {
+    if !cv.is_cratesio() {
+        let remote = &cv.remote_version;
+        return Some(if is_compatible(&cv.version, remote) { remote.clone() } else { cv.version.clone() });
+    }
+    let versions = match retry(cfg, "Lookup of versions", || registry::versions(&cv.name, cfg)) {
+        Ok(versions) => versions,
+        Err(e) => {
+            debug!("versions of {} unknown: {}", cv.name, e);
+            return None;
+        }
+    };
+    let pre = !cv.version.pre.is_empty();
+    versions.into_iter()
+        .map(|(v, _)| v)
+        .filter(|v| (pre || v.pre.is_empty()) && is_compatible(&cv.version, v))
+        .chain(Some(cv.version.clone()))
+        .max()
+}
+
+fn print_table(rows: &[[Cell; 6]], color: bool) {
+    let mut widths = HEADER.map(|h| h.chars().count());
+    for row in rows {
+        for (w, (text, _)) in widths.iter_mut().zip(row) {
+            *w = (*w).max(text.chars().count());
+        }
+    }
+    fit(&mut widths, terminal_width());
+
+    let header = HEADER.map(|h| (h.to_owned(), Some(BOLD)));
+    for row in Some(&header).into_iter().chain(rows) {
+        let line: Vec<String> = row.iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(i, ((text, style), width))| {
+                let text = truncate(text, *width);
+                // the last column is not padded to avoid trailing whitespace
+                let text = if i + 1 == widths.len() { text } else { format!("{:<width$}", text) };
+                match style {
+                    Some(style) if color => format!("{}{}{}", style, text, RESET),
+                    _ => text,
+                }
+            })
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Shrinks the widest shrinkable column until the table fits
+fn fit(widths: &mut [usize; 6], max: Option<usize>) {
+    let Some(max) = max else { return };
+    let total = |w: &[usize; 6]| w.iter().sum::<usize>() + 2 * (w.len() - 1);
+    while total(widths) > max {
+        let Some(&col) = SHRINKABLE.iter().filter(|c| widths[**c] > MIN_WIDTH).max_by_key(|c| widths[**c]) else {
+            return;
+        };
+        widths[col] -= 1;
+    }
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_owned();
+    }
+    let mut short: String = text.chars().take(width.saturating_sub(1)).collect();
+    short.push('…');
+    short
+}
+
+/// Width of the terminal on stdout, or `COLUMNS` if stdout is no terminal
+fn terminal_width() -> Option<usize> {
+    terminal_size::terminal_size()
+        .map(|(terminal_size::Width(w), _)| usize::from(w))
+        .or_else(|| env::var("COLUMNS").ok()?.parse().ok())
+}
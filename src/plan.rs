@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::env::consts::EXE_SUFFIX;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::crateversion::CrateVersion;
+use crate::registry;
+use crate::util::retry;
+
+/// Installed binaries and the crates that claim them
+pub type BinOwners = BTreeMap<String, Vec<String>>;
+
+pub fn bin_owners(installed: &[CrateVersion]) -> BinOwners {
+    let mut owners = BinOwners::new();
+    for cv in installed {
+        for binary in &cv.binaries {
+            if let Some(file) = binary.file_name() {
+                owners.entry(file.to_string_lossy().into_owned()).or_default().push(cv.name.clone());
+            }
+        }
+    }
+    owners
+}
+
+/// Checks an upgrade before the build starts. Bin targets and features of crates.io
+/// versions are looked up if the cache does not know them yet, and added to the cache.
+/// Prints the problems found and returns false if the upgrade has to be skipped.
+pub fn review(cv: &mut CrateVersion, owners: &BinOwners, cfg: &Config, cache: &mut Cache) -> bool {
+    if cv.is_cratesio() {
+        let (mut bins, mut features) = (None, None);
+        if cv.remote_bins.is_none() && !cfg.offline {
+            match retry(cfg, "Lookup of bin targets", || registry::bin_names(&cv.name, &cv.remote_version, cfg)) {
+                Ok(found) => bins = Some(found),
+                Err(e) => println!("Could not check the binaries of {} {} for conflicts: {}", cv.name, cv.remote_version, e),
+            }
+        }
+        if cv.remote_features.is_none() {
+            match retry(cfg, "Lookup of features", || registry::features(&cv.name, &cv.remote_version, cfg)) {
+                Ok(found) => features = Some(found),
+                Err(e) => println!("Could not check the features of {} {}: {}", cv.name, cv.remote_version, e),
+            }
+        }
+        if let Some(key) = Cache::key(&cv.name, &cv.source) && (bins.is_some() || features.is_some()) {
+            cache.update(&key, &cv.remote_version, bins.as_deref(), features.as_deref());
+        }
+        cv.remote_bins = cv.remote_bins.take().or(bins);
+        cv.remote_features = cv.remote_features.take().or(features);
+    }
+
+    let binaries_ok = match cv.remote_bins {
+        Some(ref bins) => {
+            report_bins(cv, bins);
+            check_conflicts(cv, bins, owners, cfg)
+        }
+        None => true,
+    };
+    let features_ok = match cv.remote_features {
+        Some(ref features) => check_features(cv, features),
+        None => true,
+    };
+    binaries_ok && features_ok
+}
+
+/// Lists bin targets added or removed by the new version
+fn report_bins(cv: &CrateVersion, bins: &[String]) {
+    let (added, removed) = bin_changes(cv, bins);
+    if !added.is_empty() {
+        println!("{} {} adds binaries: {}", cv.name, cv.remote_version, added.join(", "));
+    }
+    if !removed.is_empty() {
+        println!("{} {} removes binaries: {}", cv.name, cv.remote_version, removed.join(", "));
+    }
+}
+
+/// Bin targets of the new version that are not installed, and installed binaries it lacks
+fn bin_changes(cv: &CrateVersion, bins: &[String]) -> (Vec<String>, Vec<String>) {
+    let installed = cv.bin_names();
+    let added = bins.iter().filter(|b| !installed.contains(b)).cloned().collect();
+    let removed = installed.iter().filter(|b| !bins.contains(b)).cloned().collect();
+    (added, removed)
+}
+
+fn check_conflicts(cv: &CrateVersion, bins: &[String], owners: &BinOwners, cfg: &Config) -> bool {
+    let mut conflicts = 0;
+    for bin in bins.iter().map(|b| format!("{}{}", b, EXE_SUFFIX)) {
+        let others = owners.get(&bin).into_iter().flatten().filter(|c| **c != cv.name);
+        for other in others {
+            println!("{} {} installs {}, which belongs to {}", cv.name, cv.remote_version, bin, other);
+            conflicts += 1;
+        }
+    }
+    if conflicts > 0 && !cfg.allow_conflicts {
+        println!("Skipping {}. Use --allow-conflicts to overwrite the binaries of other crates", cv.name);
+        return false;
+    }
+    true
+}
+
+/// The installation would fail after the old version is removed if a recorded feature is gone
+fn check_features(cv: &CrateVersion, features: &[String]) -> bool {
+    let missing: Vec<&str> = cv.features.iter()
+        // `default` always exists, features of dependencies are not checked
+        .filter(|f| *f != "default" && !f.contains('/'))
+        .filter(|f| !features.contains(f))
+        .map(String::as_str)
+        .collect();
+    if missing.is_empty() {
+        return true;
+    }
+    println!("{} {} no longer has the features: {}", cv.name, cv.remote_version, missing.join(", "));
+    println!("Skipping {}. Reinstall it manually with the remaining features", cv.name);
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use semver::Version;
+    use std::path::{Path, PathBuf};
+
+    /// A crate installed in `bin` with the given binaries, named as on disk
+    fn installed(name: &str, bins: &[&str]) -> CrateVersion {
+        let mut cv = CrateVersion::new(name, Version::new(1, 0, 0));
+        cv.remote_version = Version::new(2, 0, 0);
+        let bins: Vec<PathBuf> = bins.iter().map(|b| Path::new("bin").join(format!("{}{}", b, EXE_SUFFIX))).collect();
+        cv.set_binaries(&bins);
+        cv
+    }
+
+    fn bins(names: &[&str]) -> Vec<String> {
+        names.iter().map(|b| b.to_string()).collect()
+    }
+
+    #[test]
+    fn owners_use_file_names() {
+        let owners = bin_owners(&[installed("foo", &["foo", "shared"]), installed("bar", &["shared"])]);
+        assert_eq!(owners[&format!("foo{}", EXE_SUFFIX)], ["foo"]);
+        assert_eq!(owners[&format!("shared{}", EXE_SUFFIX)], ["foo", "bar"]);
+        assert!(bin_owners(&[]).is_empty());
+    }
+
+    #[test]
+    fn conflicts_with_other_crates() {
+        let crates = [installed("foo", &["foo"]), installed("bar", &["bar"])];
+        let owners = bin_owners(&crates);
+        let mut cfg = Config::for_test(Path::new(""));
+        // the bin targets of the new version have no suffix, the files do
+        assert!(!check_conflicts(&crates[0], &bins(&["foo", "bar"]), &owners, &cfg));
+        cfg.allow_conflicts = true;
+        assert!(check_conflicts(&crates[0], &bins(&["foo", "bar"]), &owners, &cfg));
+    }
+
+    #[test]
+    fn own_and_new_binaries_are_no_conflict() {
+        let crates = [installed("foo", &["foo"]), installed("bar", &["bar"])];
+        let owners = bin_owners(&crates);
+        let cfg = Config::for_test(Path::new(""));
+        assert!(check_conflicts(&crates[0], &bins(&["foo", "foo-helper"]), &owners, &cfg));
+        assert!(check_conflicts(&crates[0], &[], &owners, &cfg));
+        assert!(check_conflicts(&crates[0], &bins(&["bar"]), &BinOwners::new(), &cfg));
+    }
+
+    #[test]
+    fn added_and_removed_binaries() {
+        let cv = installed("foo", &["foo", "foo-old"]);
+        let (added, removed) = bin_changes(&cv, &bins(&["foo", "foo-new"]));
+        assert_eq!(added, ["foo-new"]);
+        assert_eq!(removed, ["foo-old"]);
+        let (added, removed) = bin_changes(&cv, &bins(&["foo-old", "foo"]));
+        assert!(added.is_empty() && removed.is_empty());
+        let (added, removed) = bin_changes(&cv, &[]);
+        assert!(added.is_empty());
+        assert_eq!(removed, ["foo", "foo-old"]);
+        let (added, removed) = bin_changes(&installed("new", &[]), &bins(&["new"]));
+        assert_eq!(added, ["new"]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn removed_features_block_the_upgrade() {
+        let mut cv = installed("foo", &["foo"]);
+        cv.set_features(&bins(&["json", "yaml"]));
+        assert!(check_features(&cv, &bins(&["json", "yaml", "toml"])));
+        assert!(!check_features(&cv, &bins(&["json"])));
+        assert!(!check_features(&cv, &[]));
+    }
+
+    #[test]
+    fn default_and_dependency_features_are_not_checked() {
+        let mut cv = installed("foo", &["foo"]);
+        cv.set_features(&bins(&["default", "serde/derive", "json"]));
+        assert!(check_features(&cv, &bins(&["json"])));
+        assert!(check_features(&installed("bar", &["bar"]), &[]));
+    }
+}
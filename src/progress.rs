@@ -0,0 +1,122 @@
+use std::io::{stdout, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+use crate::util::fmt_age;
+
+/// Progress through a queue of crates
+#[derive(Debug)]
+pub struct Progress {
+    position: usize,
+    /// Durations of earlier upgrades of the queued crates, if known
+    estimates: Vec<Option<Duration>>,
+    started: Instant,
+    enabled: bool,
+    /// A status line that gets overwritten is on the terminal
+    transient: bool,
+}
+
+impl Progress {
+    pub fn new(estimates: Vec<Option<Duration>>, enabled: bool) -> Progress {
+        Progress {
+            position: 0,
+            estimates,
+            started: Instant::now(),
+            enabled,
+            transient: false,
+        }
+    }
+
+    /// Moves on to the next crate
+    pub fn next(&mut self) {
+        self.position += 1;
+        self.started = Instant::now();
+    }
+
+    /// Position in the queue, e.g. `3/17`
+    pub fn position(&self) -> String {
+        format!("{}/{}", self.position, self.estimates.len())
+    }
+
+    /// Time spent on the current crate
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Shows what is going on with the current crate
+    pub fn phase(&self, phase: &str) {
+        if self.enabled {
+            println!("      {} ({})", phase, fmt_age(self.elapsed()));
+        }
+    }
+
+    /// Shows a status line that is replaced by the next output. Only used on terminals.
+    pub fn status(&mut self, status: &str) {
+        if self.enabled && stdout().is_terminal() {
+            print!("\r\x1b[K[{}] {}", self.position(), status);
+            let _ = stdout().flush();
+            self.transient = true;
+        }
+    }
+
+    /// Removes the status line
+    pub fn clear(&mut self) {
+        if self.transient {
+            print!("\r\x1b[K");
+            let _ = stdout().flush();
+            self.transient = false;
+        }
+    }
+
+    /// Expected time until the queue is done. Crates without a recorded duration are
+    /// assumed to take as long as the average of the others. None if nothing is recorded.
+    pub fn remaining(&self) -> Option<Duration> {
+        let known: Vec<Duration> = self.estimates.iter().flatten().copied().collect();
+        if known.is_empty() {
+            return None;
+        }
+        let average = known.iter().sum::<Duration>() / known.len() as u32;
+        let current = self.position.saturating_sub(1);
+        let mut remaining: Duration = self.estimates.iter()
+            .skip(current)
+            .map(|e| e.unwrap_or(average))
+            .sum();
+        if self.position > 0 {
+            remaining = remaining.saturating_sub(self.elapsed().min(self.estimates[current].unwrap_or(average)));
+        }
+        Some(remaining).filter(|r| !r.is_zero())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Option<Duration> {
+        Some(Duration::from_secs(s))
+    }
+
+    #[test]
+    fn remaining_without_estimates() {
+        assert_eq!(Progress::new(Vec::new(), false).remaining(), None);
+        assert_eq!(Progress::new(vec![None, None], false).remaining(), None);
+    }
+
+    #[test]
+    fn remaining_uses_the_average_for_unknown_crates() {
+        let progress = Progress::new(vec![secs(10), None, secs(30)], false);
+        assert_eq!(progress.remaining(), secs(60));
+    }
+
+    #[test]
+    fn remaining_skips_finished_crates() {
+        let mut progress = Progress::new(vec![secs(10), secs(20), secs(40)], false);
+        progress.next();
+        progress.next();
+        // the current crate already took longer than expected
+        progress.started = Instant::now() - Duration::from_secs(30);
+        assert_eq!(progress.remaining(), secs(40));
+        progress.next();
+        progress.started = Instant::now() - Duration::from_secs(60);
+        assert_eq!(progress.remaining(), None);
+    }
+}
@@ -0,0 +1,79 @@
+use semver::Version;
+use serde_json::Value;
+
+use std::io::ErrorKind;
+use std::process::{Command, Stdio};
+
+use crate::config::Config;
+use crate::crateversion::Result;
+use crate::error::UpgradeError;
+use crate::index;
+
+const API: &str = "https://crates.io/api/v1/crates";
+const SPARSE_INDEX: &str = "https://index.crates.io";
+/// Exit codes of curl for resolver and connection errors
+const CURL_TRANSIENT: [i32; 7] = [5, 6, 7, 35, 52, 55, 56];
+const CURL_TIMEOUT: i32 = 28;
+
+/// Bin targets of a version published on crates.io, queried from the crates.io API
+pub fn bin_names(name: &str, version: &Version, cfg: &Config) -> Result<Vec<String>> {
+    let out = fetch(&format!("{}/{}/{}", API, name, version), cfg)?;
+    let val: Value = serde_json::from_slice(&out)?;
+    val["version"]["bin_names"].as_array()
+        .ok_or_else(|| UpgradeError::Parse(format!("crates.io response for {}", name)))
+        .map(|bins| bins.iter().filter_map(Value::as_str).map(String::from).collect())
+}
+
+/// Features of a version published on crates.io.
+/// Read from the local index cache if it knows the version, from the sparse index otherwise.
+pub fn features(name: &str, version: &Version, cfg: &Config) -> Result<Vec<String>> {
+    let find = |versions: Vec<(Version, Value)>| versions.into_iter()
+        .find(|(v, _)| v == version)
+        .map(|(_, entry)| index::features(&entry));
+    if let Some(features) = index::cached_versions(&cfg.cpath, name).ok().and_then(find) {
+        return Ok(features);
+    }
+    find(versions(name, cfg)?)
+        .ok_or_else(|| UpgradeError::NoCrate(format!("{} {} not found in the index", name, version)))
+}
+
+/// Versions published on crates.io that are not yanked, with their index entry.
+/// Read from the sparse index, or from the local index cache in offline mode.
+pub fn versions(name: &str, cfg: &Config) -> Result<Vec<(Version, Value)>> {
+    if cfg.offline {
+        return index::cached_versions(&cfg.cpath, name);
+    }
+    let path: Vec<String> = index::index_path(name).iter().map(|c| c.to_string_lossy().into_owned()).collect();
+    let out = fetch(&format!("{}/{}", SPARSE_INDEX, path.join("/")), cfg)?;
+    Ok(String::from_utf8_lossy(&out).lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|entry| !entry["yanked"].as_bool().unwrap_or(false))
+        .filter_map(|entry| Some((Version::parse(entry["vers"].as_str()?).ok()?, entry)))
+        .collect())
+}
+
+/// Downloads `url` with the `curl` binary
+fn fetch(url: &str, cfg: &Config) -> Result<Vec<u8>> {
+    info!("fetch {}", url);
+    let mut curl = Command::new("curl");
+    curl.args(["-sfL", "--user-agent", concat!("cargo-install-upgrade/", env!("CARGO_PKG_VERSION")), url]);
+    if let Some(timeout) = cfg.lookup_timeout {
+        curl.args(["--max-time", &timeout.as_secs().to_string()]);
+    }
+    let out = match curl.stdin(Stdio::null()).output() {
+        Ok(out) => out,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            return Err(UpgradeError::Gen(String::from("curl is needed to query crates.io, but it is not installed")));
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match out.status.code() {
+        Some(0) => {},
+        Some(CURL_TIMEOUT) => return Err(UpgradeError::Timeout(format!("fetching {} timed out", url))),
+        Some(code) if CURL_TRANSIENT.contains(&code) => {
+            return Err(UpgradeError::Transient(format!("could not fetch {} (curl error {})", url, code)));
+        }
+        _ => return Err(UpgradeError::Gen(format!("could not fetch {}", url))),
+    }
+    Ok(out.stdout)
+}
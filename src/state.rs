@@ -0,0 +1,154 @@
+use serde_json::{json, Map, Value};
+
+use std::collections::BTreeSet;
+use std::fs::{read, read_dir, read_to_string};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::crateversion::Result;
+use crate::util::{cmd_return, write_atomic};
+
+const STATE_FILE: &str = ".install-upgrade-state.json";
+
+/// Information this tool keeps about installed crates, next to cargo's own metadata
+#[derive(Debug)]
+pub struct State {
+    path: PathBuf,
+    crates: Map<String, Value>,
+    changed: BTreeSet<String>,
+}
+
+impl State {
+    /// Loads the state of the cargo home. A missing or damaged file yields an empty state.
+    pub fn load(cfg: &Config) -> State {
+        let path = cfg.cpath.join(STATE_FILE);
+        State {
+            crates: read_crates(&path),
+            path,
+            changed: BTreeSet::new(),
+        }
+    }
+
+    /// Fingerprint of the sources the crate was last installed from
+    pub fn fingerprint(&self, name: &str) -> Option<&str> {
+        self.crates.get(name)?["fingerprint"].as_str()
+    }
+
+    pub fn set_fingerprint(&mut self, name: &str, fingerprint: &str) {
+        self.entry(name).insert(String::from("fingerprint"), json!(fingerprint));
+    }
+
+    /// Time the last upgrade of the crate took
+    pub fn duration(&self, name: &str) -> Option<Duration> {
+        self.crates.get(name)?["duration"].as_u64().map(Duration::from_secs)
+    }
+
+    pub fn set_duration(&mut self, name: &str, duration: Duration) {
+        self.entry(name).insert(String::from("duration"), json!(duration.as_secs()));
+    }
+
+    /// Writes changed crates back to disk, keeping the others as they are on disk
+    pub fn store(&self) -> Result<()> {
+        if self.changed.is_empty() {
+            return Ok(());
+        }
+        let mut crates = read_crates(&self.path);
+        for name in &self.changed {
+            crates.insert(name.clone(), self.crates[name].clone());
+        }
+        let s = serde_json::to_string_pretty(&json!({ "crates": crates }))?;
+        write_atomic(&self.path, &s)
+    }
+
+    fn entry(&mut self, name: &str) -> &mut Map<String, Value> {
+        self.changed.insert(String::from(name));
+        let value = self.crates.entry(name).or_insert_with(|| json!({}));
+        if !value.is_object() {
+            *value = json!({});
+        }
+        value.as_object_mut().unwrap()
+    }
+}
+
+fn read_crates(path: &Path) -> Map<String, Value> {
+    read_to_string(path).ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .and_then(|mut v| match v["crates"].take() {
+            Value::Object(m) => Some(m),
+            _ => None,
+        })
+        .unwrap_or_default()
+}
+
+/// Identifies the state of a local source directory.
+/// Clean git work trees are identified by their commit, modified ones by a hash of the tracked files.
+/// Untracked files are ignored in git work trees like in `--pull`, so build output does not count as a change.
+/// Other directories are identified by a hash of all files.
+pub fn fingerprint(path: &Path, timeout: Option<Duration>) -> Result<String> {
+    let dir = path.to_str().unwrap();
+    let mut hash = Fnv::new();
+    if cmd_return(&["git", "-C", dir, "rev-parse", "--is-inside-work-tree"], timeout).trim() == "true" {
+        if cmd_return(&["git", "-C", dir, "status", "--porcelain", "--untracked-files=no"], timeout).trim().is_empty() {
+            let head = cmd_return(&["git", "-C", dir, "rev-parse", "HEAD"], timeout);
+            if let Some(head) = head.split_whitespace().next() {
+                return Ok(format!("commit:{}", head));
+            }
+        }
+        let tracked = cmd_return(&["git", "-C", dir, "ls-files", "-z"], timeout);
+        let mut files: Vec<&str> = tracked.split('\0').filter(|f| !f.is_empty()).collect();
+        files.sort_unstable();
+        for file in files {
+            hash_file(Path::new(file), &path.join(file), &mut hash)?;
+        }
+    } else {
+        hash_dir(path, path, &mut hash)?;
+    }
+    Ok(format!("hash:{:016x}", hash.0))
+}
+
+/// Hashes relative paths and contents of all files, skipping build output and VCS data
+fn hash_dir(root: &Path, dir: &Path, hash: &mut Fnv) -> Result<()> {
+    let mut entries: Vec<_> = read_dir(dir)?.flatten().map(|e| e.path()).collect();
+    entries.sort();
+    for entry in entries {
+        let name = entry.file_name().unwrap_or_default();
+        if name == "target" || name == ".git" {
+            continue;
+        }
+        if entry.is_dir() {
+            hash_dir(root, &entry, hash)?;
+        } else {
+            hash_file(entry.strip_prefix(root).unwrap_or(&entry), &entry, hash)?;
+        }
+    }
+    Ok(())
+}
+
+/// Hashes the relative path and the content of a file. Deleted files only contribute their path.
+fn hash_file(rel: &Path, path: &Path, hash: &mut Fnv) -> Result<()> {
+    hash.write(rel.to_string_lossy().as_bytes());
+    hash.write(&[0]);
+    match read(path) {
+        Ok(content) => hash.write(&content),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => hash.write(&[1]),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// 64 bit FNV-1a, stable across releases unlike the std hashers
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Fnv {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= u64::from(*b);
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
@@ -1,44 +1,352 @@
 extern crate dirs;
 
-use std::process::{Command, Stdio};
-use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::thread::{self, sleep, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use std::fs::*;
 use std::fs::File;
-use std::io::prelude::Read;
+use std::io::{stderr, stdout};
+use std::io::prelude::{Read, Write};
 
 use crate::error::UpgradeError;
 use crate::config::Config;
 use crate::crateversion::{CrateVersion,Result};
 use serde_json::Value;
+use crate::metadata::{read_v1, read_v2, PackageId};
 
-pub fn cmd_run(cmd: &[&str], verbose: bool) -> bool {
+/// Delay before the first retry of a transient failure
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Network errors reported by cargo, curl and git, and git's lock on its index
+const TRANSIENT_ERRORS: [&str; 12] = [
+    "failed to download from", "spurious network error", "couldn't resolve host", "could not resolve host",
+    "failed to connect to", "temporary failure in name resolution", "connection reset by peer",
+    "connection refused", "network is unreachable", "the remote end hung up unexpectedly", "early eof",
+    "index.lock': file exists",
+];
+/// Output of failed commands that will fail again
+const PERMANENT_ERRORS: [&str; 6] = [
+    "could not compile", "error[e", "failed to parse manifest", "failed to run custom build command",
+    "failed to compile", "could not find",
+];
+
+/// Set by the signal handler if SIGINT/SIGTERM arrives during an upgrade
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// True while an upgrade modifies the installation
+static CRITICAL: AtomicBool = AtomicBool::new(false);
+/// Process id of the running command, 0 if there is none
+static CHILD: AtomicU32 = AtomicU32::new(0);
+
+/// Exits on SIGINT/SIGTERM, unless an upgrade is running.
+/// Upgrades notice the signal through `interrupted` and roll back first.
+/// Commands run in their own process group, so they are killed here.
+pub fn install_signal_handler() {
+    let res = ctrlc::set_handler(|| {
+        if CRITICAL.load(Ordering::SeqCst) {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+        } else {
+            match CHILD.load(Ordering::SeqCst) {
+                0 => {},
+                pid => kill_tree(pid),
+            }
+            std::process::exit(130);
+        }
+    });
+    if let Err(e) = res {
+        warn!("Could not install signal handler: {}", e);
+    }
+}
+
+/// Marks the start and end of a section that must not be left by a signal
+pub fn set_critical(critical: bool) {
+    CRITICAL.store(critical, Ordering::SeqCst);
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Runs a command. The command is killed if the run gets interrupted or exceeds the timeout.
+/// Returns whether the command succeeded, or an error if it could not run or timed out.
+pub fn cmd_run(cmd: &[&str], verbose: bool, timeout: Option<Duration>) -> Result<bool> {
     info!("run command: {}", cmd.join(" "));
-    let mut command = Command::new(cmd[0]);
-    command.args(&cmd[1..]);
+    let mut command = new_command(cmd);
     if !verbose {
         command.stdout(Stdio::null())
-               .stderr(Stdio::null())
-               .stdin(Stdio::null());
+               .stderr(Stdio::null());
+    }
+    wait(command, cmd, timeout)
+}
+
+/// Runs a command and appends its output to the file `log`.
+/// With `echo` the output is shown on the terminal as well.
+/// Returns whether the command succeeded, or an error if it could not run or timed out.
+pub fn cmd_log(cmd: &[&str], log: &Path, echo: bool, timeout: Option<Duration>) -> Result<bool> {
+    cmd_log_env(cmd, &[], log, echo, timeout)
+}
+
+/// `cmd_log` with additional environment variables
+pub fn cmd_log_env(cmd: &[&str], env: &[(&str, OsString)], log: &Path, echo: bool, timeout: Option<Duration>) -> Result<bool> {
+    info!("run command: {} > {}", cmd.join(" "), log.display());
+    let file = OpenOptions::new().create(true).append(true).open(log).and_then(|mut f| {
+        writeln!(f, "$ {}", cmd.join(" "))?;
+        Ok(f)
+    });
+    let file = file.map_err(|e| UpgradeError::Gen(format!("could not write log {}: {}", log.display(), e)))?;
+    let mut command = new_command(cmd);
+    command.envs(env.iter().map(|(k, v)| (k, v)));
+    if !echo {
+        let out = file.try_clone()?;
+        command.stdout(out).stderr(file);
+        return wait(command, cmd, timeout);
     }
-    let ret = command.status();
-    match ret {
-        Ok(exit) => exit.success(),
-        Err(_) => {
-            println!("Error running: {}", &cmd.join(" "));
-            false
-        },
+
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = spawn(&mut command, cmd)?;
+    let log = Arc::new(Mutex::new(file));
+    let readers = [
+        child.stdout.take().map(|out| tee(out, log.clone(), Box::new(stdout()))),
+        child.stderr.take().map(|err| tee(err, log.clone(), Box::new(stderr()))),
+    ];
+    let success = wait_child(&mut child, cmd, timeout);
+    readers.into_iter().flatten().for_each(|r| { let _ = r.join(); });
+    success
+}
+
+/// Runs a command and returns its stdout.
+/// Failures are transient if the error output looks like a network or locking problem.
+pub fn cmd_output(cmd: &[&str], timeout: Option<Duration>) -> Result<String> {
+    info!("run command: {}", cmd.join(" "));
+    let mut command = new_command(cmd);
+    command.stdout(Stdio::piped())
+           .stderr(Stdio::piped());
+    let mut child = spawn(&mut command, cmd)?;
+    let out = child.stdout.take().map(|o| thread::spawn(move || read_all(o)));
+    let err = child.stderr.take().map(|e| thread::spawn(move || read_all(e)));
+    let success = wait_child(&mut child, cmd, timeout);
+    let out = out.and_then(|t| t.join().ok()).unwrap_or_default();
+    let err = err.and_then(|t| t.join().ok()).unwrap_or_default();
+    if success? {
+        return Ok(out);
     }
+    debug!("{} failed: {}", cmd.join(" "), err);
+    // git and cargo start their messages with `fatal:` or `error:`
+    let reason = err.lines()
+        .find_map(|l| l.strip_prefix("error:").or_else(|| l.strip_prefix("fatal:")))
+        .or_else(|| err.lines().rfind(|l| !l.trim().is_empty()))
+        .unwrap_or("failed");
+    Err(classify(&err, format!("{}: {}", cmd[..2.min(cmd.len())].join(" "), reason.trim())))
 }
 
-pub fn cmd_return(cmd: &[&str]) -> String {
-    info!("ret command: {}", cmd.join(" "));
+/// Error for a failed command with the output `output`
+pub fn classify(output: &str, msg: String) -> UpgradeError {
+    let output = output.to_lowercase();
+    let permanent = PERMANENT_ERRORS.iter().any(|p| output.contains(p));
+    let transient = output.lines().any(|l| TRANSIENT_ERRORS.iter().any(|p| l.contains(p)) || http_transient(l));
+    if !permanent && transient {
+        UpgradeError::Transient(msg)
+    } else {
+        UpgradeError::Gen(msg)
+    }
+}
+
+/// Rate limits and server errors of a registry, e.g.
+/// `failed to get successful http response from `https://index.crates.io/config.json`, got 503`
+fn http_transient(line: &str) -> bool {
+    line.contains("failed to get successful http response")
+        && line.rsplit_once("got ").is_some_and(|(_, code)| code.trim() == "429" || code.trim().starts_with('5'))
+}
+
+/// Runs `f` until it succeeds, fails with a permanent error or the retries are used up.
+/// The delay between the attempts doubles each time.
+pub fn retry<T, F>(cfg: &Config, what: &str, mut f: F) -> Result<T> where F: FnMut() -> Result<T> {
+    let mut delay = RETRY_DELAY;
+    for _ in 0..cfg.retries {
+        match f() {
+            Err(e) if e.is_transient() && !interrupted() => {
+                if !cfg.quiet() {
+                    println!("{} failed: {}. Retrying in {}", what, e, fmt_age(delay));
+                }
+                sleep(delay);
+                delay *= 2;
+            }
+            res => return res,
+        }
+    }
+    f()
+}
+
+/// A command that does not read from the terminal.
+/// It runs in a process group of its own, which allows to kill the whole tree. Being in the
+/// background it would be stopped when reading the terminal, so git and ssh must not prompt.
+fn new_command(cmd: &[&str]) -> Command {
     let mut command = Command::new(cmd[0]);
-    command.args(&cmd[1..]);
-    let out = command.output().unwrap_or_else(|e| panic!("{}", e));
-    String::from_utf8(out.stdout).unwrap_or_else(|_| "ERROR".to_owned())
+    command.args(&cmd[1..]).stdin(Stdio::null());
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    if std::env::var_os("GIT_SSH_COMMAND").is_none() {
+        command.env("GIT_SSH_COMMAND", "ssh -o BatchMode=yes");
+    }
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    command
+}
+
+fn spawn(command: &mut Command, cmd: &[&str]) -> Result<Child> {
+    match command.spawn() {
+        Ok(child) => {
+            CHILD.store(child.id(), Ordering::SeqCst);
+            Ok(child)
+        }
+        Err(e) => Err(UpgradeError::Gen(format!("could not run {}: {}", cmd.join(" "), e))),
+    }
+}
+
+fn wait(mut command: Command, cmd: &[&str], timeout: Option<Duration>) -> Result<bool> {
+    let mut child = spawn(&mut command, cmd)?;
+    wait_child(&mut child, cmd, timeout)
+}
+
+/// Waits for a command. It is killed with all its children if the run is interrupted
+/// or the timeout is exceeded.
+fn wait_child(child: &mut Child, cmd: &[&str], timeout: Option<Duration>) -> Result<bool> {
+    let start = Instant::now();
+    let res = loop {
+        match child.try_wait() {
+            Ok(Some(exit)) => break Ok(exit.success()),
+            Ok(None) if interrupted() => {
+                kill_tree(child.id());
+                let _ = child.wait();
+                break Ok(false);
+            }
+            Ok(None) if timeout.is_some_and(|t| start.elapsed() > t) => {
+                kill_tree(child.id());
+                let _ = child.wait();
+                break Err(UpgradeError::Timeout(format!("{} timed out after {}",
+                    cmd[..2.min(cmd.len())].join(" "), fmt_age(start.elapsed()))));
+            }
+            Ok(None) => sleep(Duration::from_millis(50)),
+            Err(e) => break Err(UpgradeError::Gen(format!("error waiting for {}: {}", cmd.join(" "), e))),
+        }
+    };
+    CHILD.store(0, Ordering::SeqCst);
+    res
+}
+
+/// Kills a process and everything it started
+fn kill_tree(pid: u32) {
+    let mut command = if cfg!(windows) {
+        let mut c = Command::new("taskkill");
+        c.args(["/T", "/F", "/PID", &pid.to_string()]);
+        c
+    } else {
+        // the negative id addresses the process group
+        let mut c = Command::new("kill");
+        c.args(["-KILL", "--", &format!("-{}", pid)]);
+        c
+    };
+    let _ = command.stdout(Stdio::null()).stderr(Stdio::null()).status();
+}
+
+/// Copies the output of a child process to the log and to the terminal
+fn tee<R>(mut from: R, log: Arc<Mutex<File>>, mut to: Box<dyn Write + Send>) -> JoinHandle<()>
+    where R: Read + Send + 'static {
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        while let Ok(n) = from.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            if let Ok(mut log) = log.lock() {
+                let _ = log.write_all(&buf[..n]);
+            }
+            let _ = to.write_all(&buf[..n]);
+        }
+    })
+}
+
+fn read_all<R: Read>(mut from: R) -> String {
+    let mut s = String::new();
+    let _ = from.read_to_string(&mut s);
+    s
+}
+
+/// The last `n` lines of a file
+pub fn tail(path: &Path, n: usize) -> Vec<String> {
+    let content = read_to_string(path).unwrap_or_default();
+    let lines: Vec<&str> = content.lines().collect();
+    lines[lines.len().saturating_sub(n)..].iter().map(|l| (*l).to_owned()).collect()
+}
+
+/// Runs a command and returns its stdout, also if it failed.
+/// Empty if the command could not run, output up to the kill if it timed out.
+pub fn cmd_return(cmd: &[&str], timeout: Option<Duration>) -> String {
+    info!("ret command: {}", cmd.join(" "));
+    let mut command = new_command(cmd);
+    command.stdout(Stdio::piped())
+           .stderr(Stdio::null());
+    let mut child = match spawn(&mut command, cmd) {
+        Ok(child) => child,
+        Err(e) => {
+            debug!("{}", e);
+            return String::new();
+        }
+    };
+    let out = child.stdout.take().map(|o| thread::spawn(move || read_all(o)));
+    if let Err(e) = wait_child(&mut child, cmd, timeout) {
+        debug!("{}", e);
+    }
+    out.and_then(|t| t.join().ok()).unwrap_or_default()
 }
 
 
+/// Formats a duration as a rough human readable age
+pub fn fmt_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    let (n, unit) = match secs {
+        0..=119 => (secs, "second"),
+        120..=7199 => (secs / 60, "minute"),
+        7200..=172_799 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+    format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" })
+}
+
+/// Matches `text` against a pattern with the wildcards `*` and `?`
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+/// Replaces a file by renaming a temporary file over it, so readers never see partial content
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    write(&tmp, contents)?;
+    rename(&tmp, path)?;
+    Ok(())
+}
+
 pub fn search_cargo_data() -> Option<PathBuf> {
     debug!("Search for cargohome");
     let mut candidates = Vec::new();
@@ -65,67 +373,232 @@ pub fn search_cargo_data() -> Option<PathBuf> {
 }
 
 pub fn read_installed_packages(cfg: &Config) -> Result<Vec<CrateVersion>> {
-    let mut path = cfg.cpath.clone();
-    path.push(".crates2.json");
+    let installs = match read_v2(&cfg.cpath) {
+        Ok(installs) => installs,
+        Err(UpgradeError::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound => return read_legacy_packages(cfg),
+        Err(e) => return Err(e),
+    };
     let mut out = Vec::new();
-
-    let mut file = File::open(&path)?;
-    let mut s = String::new();
-    let _ = file.read_to_string(&mut s);
-
-    let v: Value = serde_json::from_str(&s)?;
-
-    let installs = v["installs"].as_object().ok_or_else(|| UpgradeError::from("json not valid"))?;
-    for (key, value) in installs {
-
-        let crat = key.as_str().to_owned();
-        let elements: Vec<&str> = crat.split(' ').collect();
-        let address = elements[2].trim_matches(|c| c == '(' || c == ')');
-        let mut topush = CrateVersion::new_fromstr(elements[0], elements[1]);
-        let addr: Vec<&str> = address.split('+').collect();
-        match addr[0] {
-            "git" => {
-                let mut elem = addr[1].split('#');
-                topush.set_repo(elem.next().unwrap(), elem.next().unwrap());
-            },
-            "path" if cfg!(target_os = "windows") => {
-                topush.set_path(addr[1].trim_start_matches("file:///"));
-            },
-            "path" => {
-                topush.set_path(addr[1].trim_start_matches("file://"));
-            },
-            _ => {},
-        };
+    for (key, value) in &installs {
+        let Some(mut topush) = crate_version(key) else { continue };
 
         let details = value.as_object().ok_or_else(|| UpgradeError::from("details-segment not valid"))?;
-        
-        if let Some(binaries) = details["bins"].as_array() {
-            let mut paths_binaries = Vec::new();
-            for binaryvalue in binaries {
-                if let Some(binarystr) = binaryvalue.as_str() {
-                    let mut path = cfg.cpath.clone();
-                    path.push("bin");
-                    path.push(binarystr);
-                    paths_binaries.push(path);
-                }
-            }
-            topush.set_binaries(&paths_binaries);
+        if let Some(binaries) = details.get("bins").and_then(Value::as_array) {
+            topush.set_binaries(&bin_paths(cfg, binaries.iter().filter_map(Value::as_str)));
         }
 
-
-
-        if let Some(features) = details["features"].as_array() {
+        if let Some(features) = details.get("features").and_then(Value::as_array) {
             let mut feature_list = Vec::new();
             for binaryvalue in features {
                 if let Some(binarystr) = binaryvalue.as_str() {
                     feature_list.push(String::from(binarystr));
                 }
-                topush.set_features(&feature_list);
             }
+            topush.set_features(&feature_list);
         }
 
+        topush.all_features = value["all_features"].as_bool().unwrap_or(false);
+        topush.no_default_features = value["no_default_features"].as_bool().unwrap_or(false);
+        topush.profile = value["profile"].as_str().map(String::from);
+        topush.target = value["target"].as_str().map(String::from);
+        // `rustc -vV` output, the first line holds the version
+        topush.rustc = value["rustc"].as_str().and_then(|r| r.lines().next()).map(String::from);
+
         debug!("{:?}", topush);
         out.push(topush);
     }
     Ok(out)
 }
+
+/// Reads older install roots that only have `.crates.toml`. It records no features and build settings.
+fn read_legacy_packages(cfg: &Config) -> Result<Vec<CrateVersion>> {
+    let mut out = Vec::new();
+    for (key, bins) in &read_v1(&cfg.cpath)? {
+        let Some(mut topush) = crate_version(key) else { continue };
+        let bins = bins.as_array().into_iter().flatten().filter_map(toml::Value::as_str);
+        topush.set_binaries(&bin_paths(cfg, bins));
+        topush.features_known = false;
+        debug!("{:?}", topush);
+        out.push(topush);
+    }
+    Ok(out)
+}
+
+/// The crate recorded under a package id, None after reporting an invalid id
+fn crate_version(key: &str) -> Option<CrateVersion> {
+    match PackageId::parse(key).and_then(|id| id.crate_version()) {
+        Ok(cv) => Some(cv),
+        Err(e) => {
+            eprintln!("Ignoring invalid install record: {}. Run `cargo install-upgrade doctor`", e);
+            None
+        }
+    }
+}
+
+fn bin_paths<'a>(cfg: &Config, names: impl Iterator<Item = &'a str>) -> Vec<PathBuf> {
+    names.map(|name| cfg.cpath.join("bin").join(name)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crateversion::PackageSource;
+    use crate::metadata::{CRATES_V1, CRATES_V2};
+
+    fn transient(output: &str) -> bool {
+        classify(output, String::from("failed")).is_transient()
+    }
+
+    #[test]
+    fn classify_network_errors() {
+        assert!(transient("\
+error: failed to download from `https://static.crates.io/crates/ripgrep/ripgrep-14.1.1.crate`
+
+Caused by:
+  [6] Couldn't resolve host name (Could not resolve host: static.crates.io)"));
+        assert!(transient("\
+warning: spurious network error (3 tries remaining): [7] Couldn't connect to server (Failed to connect to index.crates.io port 443 after 2 ms: Connection refused)
+error: failed to get `serde` as a dependency of package `foo v0.1.0`"));
+        assert!(transient("fatal: unable to access 'https://github.com/foo/bar/': Could not resolve host: github.com"));
+        assert!(transient("fatal: Unable to create '/home/u/.cargo/git/db/bar/index.lock': File exists."));
+        assert!(transient("error: failed to get successful HTTP response from `https://index.crates.io/config.json` (18.66.2.1), got 503"));
+        assert!(transient("error: failed to get successful HTTP response from `https://index.crates.io/3/f/foo`, got 429"));
+    }
+
+    #[test]
+    fn classify_permanent_errors() {
+        assert!(!transient("\
+error: failed to run custom build command for `openssl-sys v0.9.103`
+
+Caused by:
+  process didn't exit successfully: `/tmp/target/release/build/openssl-sys/build-script-main` (exit status: 101)
+  --- stderr
+  Could not find directory of OpenSSL installation, and this `-sys` crate cannot proceed without this knowledge."));
+        assert!(!transient("\
+error[E0425]: cannot find value `x` in this scope
+error: could not compile `foo` (bin \"foo\") due to 1 previous error
+error: failed to compile `foo v0.1.0`, intermediate artifacts can be found at `/tmp/cargo-installXYZ`."));
+        assert!(!transient("error: could not find `nosuchcrate` in registry `crates-io` with version `*`"));
+        assert!(!transient("error: failed to get successful HTTP response from `https://index.crates.io/no/su/nosuch`, got 404"));
+        // a build that fails while downloading in its build script will fail again
+        assert!(!transient("\
+error: failed to run custom build command for `foo-sys v1.0.0`
+  curl: (6) Could not resolve host: example.com"));
+    }
+
+    #[test]
+    fn classify_empty_output() {
+        assert!(!transient(""));
+    }
+
+    #[test]
+    fn wildcard_match_patterns() {
+        assert!(wildcard_match("", ""));
+        assert!(!wildcard_match("", "rg"));
+        assert!(wildcard_match("*", ""));
+        assert!(wildcard_match("*", "ripgrep"));
+        assert!(wildcard_match("cargo-*", "cargo-edit"));
+        assert!(!wildcard_match("cargo-*", "cargo"));
+        assert!(wildcard_match("r?", "rg"));
+        assert!(!wildcard_match("r?", "r"));
+        assert!(wildcard_match("*-*-*", "a-b-c"));
+        assert!(wildcard_match("*grep", "ripgrep"));
+        assert!(!wildcard_match("*grep", "grepper"));
+        assert!(wildcard_match("c**t", "cat"));
+    }
+
+    #[test]
+    fn fmt_age_units() {
+        assert_eq!(fmt_age(Duration::ZERO), "0 seconds");
+        assert_eq!(fmt_age(Duration::from_secs(1)), "1 second");
+        assert_eq!(fmt_age(Duration::from_secs(119)), "119 seconds");
+        assert_eq!(fmt_age(Duration::from_secs(120)), "2 minutes");
+        assert_eq!(fmt_age(Duration::from_secs(7199)), "119 minutes");
+        assert_eq!(fmt_age(Duration::from_secs(7200)), "2 hours");
+        assert_eq!(fmt_age(Duration::from_secs(172_799)), "47 hours");
+        assert_eq!(fmt_age(Duration::from_secs(172_800)), "2 days");
+    }
+
+    const V1: &str = r#"[v1]
+"ripgrep 14.1.0 (registry+https://github.com/rust-lang/crates.io-index)" = ["rg"]
+"foo 0.1.0 (git+https://example.com/foo#0123abc)" = ["foo", "foo-cli"]
+"bar 0.2.0 (path+file:///home/u/bar)" = []
+"not an id" = ["x"]
+"#;
+
+    const V2: &str = r#"{"installs": {
+"ripgrep 14.1.0 (registry+https://github.com/rust-lang/crates.io-index)": {"version_req": null, "bins": ["rg"],
+    "features": ["pcre2"], "all_features": false, "no_default_features": false, "profile": "release",
+    "target": "x86_64-unknown-linux-gnu", "rustc": "rustc 1.80.0\nbinary: rustc"},
+"foo 0.1.0 (git+https://example.com/foo#0123abc)": {"version_req": null, "bins": ["foo", "foo-cli"],
+    "features": [], "all_features": true, "no_default_features": false, "profile": "release",
+    "target": "x86_64-unknown-linux-gnu", "rustc": "rustc 1.80.0"},
+"bar 0.2.0 (path+file:///home/u/bar)": {"version_req": null, "bins": [], "features": [],
+    "all_features": false, "no_default_features": false, "profile": "dev", "target": null, "rustc": null}
+}}"#;
+
+    /// An install root with `.crates.toml` and optionally `.crates2.json`
+    fn install_root(v2: bool) -> tempdir::TempDir {
+        let tmp = tempdir::TempDir::new("root").unwrap();
+        write(tmp.path().join(CRATES_V1), V1).unwrap();
+        if v2 {
+            write(tmp.path().join(CRATES_V2), V2).unwrap();
+        }
+        tmp
+    }
+
+    /// Name, version, source and binaries of each crate, sorted by name
+    fn inventory(installed: &[CrateVersion]) -> Vec<(String, String, PackageSource, Vec<PathBuf>)> {
+        let mut inventory: Vec<_> = installed.iter()
+            .map(|cv| (cv.name.clone(), cv.version.to_string(), cv.source.clone(), cv.binaries.clone()))
+            .collect();
+        inventory.sort_by(|a, b| a.0.cmp(&b.0));
+        inventory
+    }
+
+    #[test]
+    fn legacy_root_has_the_same_inventory() {
+        let (legacy, current) = (install_root(false), install_root(true));
+        let v1 = read_installed_packages(&Config::for_test(legacy.path())).unwrap();
+        let v2 = read_installed_packages(&Config::for_test(current.path())).unwrap();
+        assert_eq!(v1.len(), 3);
+        // binary paths are in the respective root
+        let strip = |mut inv: Vec<(String, String, PackageSource, Vec<PathBuf>)>, root: &Path| {
+            inv.iter_mut().for_each(|c| c.3.iter_mut().for_each(|b| *b = b.strip_prefix(root).unwrap().to_path_buf()));
+            inv
+        };
+        assert_eq!(strip(inventory(&v1), legacy.path()), strip(inventory(&v2), current.path()));
+    }
+
+    #[test]
+    fn legacy_root_has_no_build_settings() {
+        let legacy = install_root(false);
+        for cv in read_installed_packages(&Config::for_test(legacy.path())).unwrap() {
+            assert!(!cv.features_known, "{}", cv.name);
+            assert!(cv.features.is_empty() && !cv.all_features && !cv.no_default_features);
+            assert_eq!((cv.profile, cv.target, cv.rustc), (None, None, None));
+        }
+        let current = install_root(true);
+        let installed = read_installed_packages(&Config::for_test(current.path())).unwrap();
+        let rg = installed.iter().find(|cv| cv.name == "ripgrep").unwrap();
+        assert!(rg.features_known);
+        assert_eq!(rg.features, ["pcre2"]);
+        assert_eq!(rg.profile.as_deref(), Some("release"));
+        assert_eq!(rg.rustc.as_deref(), Some("rustc 1.80.0"));
+    }
+
+    #[test]
+    fn missing_and_damaged_roots() {
+        let tmp = tempdir::TempDir::new("root").unwrap();
+        let cfg = Config::for_test(tmp.path());
+        assert!(read_installed_packages(&cfg).is_err());
+        write(tmp.path().join(CRATES_V1), "").unwrap();
+        assert!(read_installed_packages(&cfg).is_err());
+        write(tmp.path().join(CRATES_V1), "[v1]\n").unwrap();
+        assert!(read_installed_packages(&cfg).unwrap().is_empty());
+        // a damaged `.crates2.json` is an error, not a reason to fall back
+        write(tmp.path().join(CRATES_V1), V1).unwrap();
+        write(tmp.path().join(CRATES_V2), "{").unwrap();
+        assert!(read_installed_packages(&cfg).is_err());
+    }
+}